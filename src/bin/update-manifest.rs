@@ -4,13 +4,13 @@
 // That file is for use by the actual updater
 
 use std::io::{stdout, stderr, SeekFrom};
-use std::path::PathBuf;
-use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::fs::{File, OpenOptions, create_dir_all};
 use std::error::Error;
 use std::io::prelude::*;
 use std::env;
 use std::collections::{BTreeSet, BTreeMap};
-use std::process::Command;
+use std::process::{self, Command, Stdio};
 // pull in checksums
 extern crate checksums;
 use checksums::ops::create_hashes;
@@ -18,16 +18,88 @@ use checksums::ops::create_hashes;
 extern crate chatpack_updater;
 use chatpack_updater::version::Version;
 use chatpack_updater::utils;
+use chatpack_updater::changelog;
+use chatpack_updater::patch;
+use chatpack_updater::compression;
+use chatpack_updater::source;
+use chatpack_updater::versions;
+use chatpack_updater::policy;
+use chatpack_updater::components;
+use chatpack_updater::paths;
+use chatpack_updater::objects;
+use chatpack_updater::bundle;
+use chatpack_updater::serve;
+use chatpack_updater::git::GitRepo;
+use chatpack_updater::manifest::{self, DiffFormat};
 
 extern crate serde_json;
 
-extern crate gitignore;
 
 // get constants
 use chatpack_updater::constants::*;
 
 
 fn main () {
+    let args: Vec<String> = env::args().collect();
+    // `update-manifest diff <old> <new>` compares two existing manifests instead of building one
+    if args.len() > 1 && args[1] == "diff" {
+        diff_manifests(&args[2..]);
+        return;
+    }
+    // `update-manifest versions` rebuilds the version index from git history
+    if args.len() > 1 && args[1] == "versions" {
+        rebuild_versions();
+        return;
+    }
+    // `update-manifest explain-ignore <path>` says whether a file under the chatpack directory is ignored and why; `list-ignored` lists everything that is
+    if args.len() > 1 && (args[1] == "explain-ignore" || args[1] == "list-ignored") {
+        let mut cp_path: PathBuf = env::current_dir().unwrap();
+        cp_path.push(&TARGET_DIR);
+        if args[1] == "list-ignored" {
+            for path in utils::list_ignored(&cp_path) {
+                println!("{}", path);
+            }
+        } else {
+            match args.get(2) {
+                Some(path) => println!("{}", utils::explain_ignore(&cp_path, path)),
+                None => println!("Usage: update-manifest explain-ignore <path, relative to the {} directory>", TARGET_DIR),
+            }
+        }
+        return;
+    }
+    // run as the post-commit git hook (or as `update-manifest post-commit`), record what the commit that was just made published
+    let run_as = env::current_exe().ok().and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned())).unwrap_or_default();
+    if run_as == "post-commit" || (args.len() > 1 && args[1] == "post-commit") {
+        post_commit(&args);
+        return;
+    }
+    // `update-manifest pin [commit]` publishes which commit updaters should get the manifest and files from
+    if args.len() > 1 && args[1] == "pin" {
+        pin(args.get(2));
+        return;
+    }
+    // `update-manifest bundle --from <version>` packages everything changed since a version for updating offline
+    if args.len() > 1 && args[1] == "bundle" {
+        match utils::arg_value(&args, "--from") {
+            Some(from) => make_bundle(&from, utils::arg_value(&args, "--output")),
+            None => println!("Usage: update-manifest bundle --from <version> [--output <file>]"),
+        }
+        return;
+    }
+    // `update-manifest serve` hosts this repository over http, so updaters on the same network can update from it
+    if args.len() > 1 && args[1] == "serve" {
+        let address = utils::arg_value(&args, "--address").unwrap_or_else(|| DEFAULT_SERVE_ADDRESS.to_string());
+        let root: PathBuf = env::current_dir().unwrap();
+        if !root.join(MANIFEST_FILENAME).exists() {
+            println!("There's no {} here; build the manifest before serving it.", MANIFEST_FILENAME);
+            return;
+        }
+        println!("Serving {} on {}; updaters can use it with `chatpack-updater --source http://<this computer's address>:<port>/`. Press ctrl+c to stop.", root.display(), address);
+        if let Err(why) = serve::serve(&root, &address) {
+            println!("{}", why);
+        }
+        return;
+    }
     // set the chatpack path variable to the current working directory
     let mut cp_path: PathBuf = env::current_dir().unwrap();
     // then add `target_dir` to that, making `cp_path` the full path to the chatpack directory
@@ -37,63 +109,8 @@ fn main () {
         println!("The '{}' directory doesn't exist; unable to create / update manifest.", TARGET_DIR);
         return;
     }
-    // update the version        
-    // do this first so the generated file gets added to the hash manifest
-    // since the lines below are in different scopes (and thus variables defined won't be valid outside of them), I declare the ones I want to keep here
-    // clone cp_path (so it's a new object, rather than taking ownership) so cp_version_path can add to it
     let mut cp_version_path = cp_path.clone();
     cp_version_path.push(VERSION_FILENAME);
-    // Check to see if there's an existing version file
-    if cp_version_path.exists() == false {
-        println!("{}'s version file not found; one will be created.", TARGET_DIR);
-    }
-    let mut version_file;
-    let mut version;
-    // if a version file doesn't exist, create one and populate it with the current version (based on the date)
-    // Otherwise, read what does exist, convert it to a `Version`, and .update() it
-    if cp_version_path.exists() == false {
-        version_file = match File::create(&cp_version_path) {
-            Err(why) => panic!("couldn't create {}: {}", cp_version_path.display(), why.description()),
-            Ok(file) => file,
-        };
-        // get current date-based version, for use further down
-        version = Version::new();
-    } else {
-        // the version file exists, so we need to read it into a string, convert that to a `Version`, run .update(), and then seek to the start of the file so it can be written out
-        version_file = match OpenOptions::new().read(true).write(true).open(&cp_version_path) {
-            Ok(mut file) => {
-                let mut str_ver: String = String::new();
-                // now read all the bytes in the opened file into a string
-                match file.read_to_string(&mut str_ver) {
-                    Ok(_) => (),
-                    Err(why) => panic!("Can't read version file {}: {}", cp_version_path.display(), why.description()),
-                }
-                str_ver = str_ver.trim().into();
-                if str_ver.is_empty() {
-                    // file is empty for some reason; use the current date-based version
-                    println!("Warning: the version file is empty; using a new version string because an old one doesn't exist to update.");
-                    version = Version::new();
-                } else {
-                    version = Version::from_string(&str_ver);
-                    version.update();
-                }
-                // delete the current contents of the file so it can be written to later with just the newly-created version as a string
-                file.seek(SeekFrom::Start(0)).expect("Can't seek to offset 0 in the version file to overwrite it.");
-                match file.set_len(0){
-                    Ok(_) => (),
-                    Err(why) => panic!("Can't overwrite {}: {}", cp_version_path.display(), why.description()),
-                }
-                // return version_file
-                file
-            },
-            Err(why) => panic!("Can't read from {}: {}", cp_version_path.display(), why.description()),
-        };
-    } // end the exists if block
-    match version_file.write_all(version.to_string().as_bytes()) {
-        Ok(_) => version_file.flush().expect("Can't write version out to file."),
-        Err(why) => panic!("Can't write new version to {}: {}", cp_version_path.display(), why.description()),
-    }
-    
     // check if there's an existing hash manifest
     let mut cp_manifest_path: PathBuf = env::current_dir().unwrap();
     cp_manifest_path.push(&MANIFEST_FILENAME);
@@ -103,23 +120,15 @@ fn main () {
         println!("Rebuilding {}'s manifest...", TARGET_DIR);
     }
     let mut ignores: BTreeSet<String> = BTreeSet::new();
-    let mut standard_ignores_path = cp_path.clone();
-    standard_ignores_path.push(STANDARD_UPDATER_IGNORE_FILENAME);
-    let mut custom_ignores_path = cp_path.clone();
-    custom_ignores_path.push(CUSTOM_UPDATER_IGNORE_FILENAME);
-    let mut ignore_files: Vec<gitignore::File> = vec!();
-    if standard_ignores_path.exists() {
-        ignore_files.push(gitignore::File::new(&standard_ignores_path).unwrap());
-    }
-    if custom_ignores_path.exists() {
-        ignore_files.push(gitignore::File::new(&custom_ignores_path).unwrap())
-    }
-    if ignore_files.is_empty() == false {
+    let ignore_rules = utils::IgnoreRules::load(&cp_path);
+    if ignore_rules.is_empty() == false {
         // walk our current directory recursively and add relative paths of ignored files and dirs
-        ignores.append(&mut utils::ignored_files(&cp_path, ignore_files));
+        ignores.append(&mut utils::ignored_files(&cp_path, &ignore_rules));
     }
+    // hash everything except the version file first; whether or not the version gets bumped depends on if anything else changed
+    ignores.insert(VERSION_FILENAME.to_string());
     let max_recursion: Option<usize> = Some(10);
-    let hashes: BTreeMap<String, String> = create_hashes(&cp_path,
+    let mut hashes: BTreeMap<String, String> = create_hashes(&cp_path,
         ignores,
         ALGO,
         max_recursion,
@@ -128,6 +137,87 @@ fn main () {
         stdout(),
         &mut stderr()
     );
+    hashes.remove(VERSION_FILENAME);
+    // warn about paths that won't work on Windows; with --strict, refuse to build the manifest at all, so CI and the git hook can block the commit
+    if !lint_paths(&hashes) && args.iter().any(|a| a == "--strict") {
+        println!("Not writing the manifest, since --strict was given.");
+        process::exit(1);
+    }
+    // manifests always use the canonical form of each path, whatever system they're built on
+    hashes = manifest::canonicalize(hashes);
+    // compare what we just hashed against the existing manifest (if there is one), leaving out the version file and anything ignored
+    let mut old_hashes: BTreeMap<String, String> = BTreeMap::new();
+    if cp_manifest_path.exists() {
+        old_hashes = match File::open(&cp_manifest_path) {
+            Ok(file) => manifest::canonicalize(serde_json::from_reader(file).unwrap_or_default()),
+            Err(why) => panic!("Can't read the existing manifest {}: {}", cp_manifest_path.display(), why),
+        };
+    }
+    let content_changed = if cp_manifest_path.exists() {
+        let mut old_content = old_hashes.clone();
+        old_content.remove(VERSION_FILENAME);
+        utils::content_hashes(&old_content) != utils::content_hashes(&hashes)
+    } else {
+        true
+    };
+
+    // update the version, but only if the content actually changed (or there's no version yet)
+    if content_changed || cp_version_path.exists() == false {
+        // Check to see if there's an existing version file
+        if cp_version_path.exists() == false {
+            println!("{}'s version file not found; one will be created.", TARGET_DIR);
+        }
+        let mut version_file;
+        let mut version;
+        // if a version file doesn't exist, create one and populate it with the current version (based on the date)
+        // Otherwise, read what does exist, convert it to a `Version`, and .update() it
+        if cp_version_path.exists() == false {
+            version_file = match File::create(&cp_version_path) {
+                Err(why) => panic!("couldn't create {}: {}", cp_version_path.display(), why.description()),
+                Ok(file) => file,
+            };
+            // get current date-based version, for use further down
+            version = Version::new();
+        } else {
+            // the version file exists, so we need to read it into a string, convert that to a `Version`, run .update(), and then seek to the start of the file so it can be written out
+            version_file = match OpenOptions::new().read(true).write(true).open(&cp_version_path) {
+                Ok(mut file) => {
+                    let mut str_ver: String = String::new();
+                    // now read all the bytes in the opened file into a string
+                    match file.read_to_string(&mut str_ver) {
+                        Ok(_) => (),
+                        Err(why) => panic!("Can't read version file {}: {}", cp_version_path.display(), why.description()),
+                    }
+                    str_ver = str_ver.trim().into();
+                    if str_ver.is_empty() {
+                        // file is empty for some reason; use the current date-based version
+                        println!("Warning: the version file is empty; using a new version string because an old one doesn't exist to update.");
+                        version = Version::new();
+                    } else {
+                        version = Version::from_string(&str_ver);
+                        version.update();
+                    }
+                    // delete the current contents of the file so it can be written to later with just the newly-created version as a string
+                    file.seek(SeekFrom::Start(0)).expect("Can't seek to offset 0 in the version file to overwrite it.");
+                    match file.set_len(0){
+                        Ok(_) => (),
+                        Err(why) => panic!("Can't overwrite {}: {}", cp_version_path.display(), why.description()),
+                    }
+                    // return version_file
+                    file
+                },
+                Err(why) => panic!("Can't read from {}: {}", cp_version_path.display(), why.description()),
+            };
+        } // end the exists if block
+        match version_file.write_all(version.to_string().as_bytes()) {
+            Ok(_) => version_file.flush().expect("Can't write version out to file."),
+            Err(why) => panic!("Can't write new version to {}: {}", cp_version_path.display(), why.description()),
+        }
+    } else {
+        println!("Nothing has changed since the last manifest was built; leaving the version as is.");
+    }
+    // the version file is part of the pack, so add it's hash now that it's contents are final
+    hashes.insert(VERSION_FILENAME.to_string(), checksums::hash_file(&cp_version_path, ALGO));
     // now catch json errors with a match
     let j = match serde_json::to_string(&hashes) {
         Err(why) => panic!("Couldn't create a json representation of the hash manifest: {}", why.description()),
@@ -147,6 +237,111 @@ fn main () {
         }, // end of the ok block
         Err(why) => panic!("couldn't create or open {}: {}", cp_manifest_path.display(), why.description()),
     } // end of the manifest file open error checking block
+
+    // optionally create binary patches from the previous version of each large modified file to the new one
+    let mut cp_patch_index_path: PathBuf = env::current_dir().unwrap();
+    cp_patch_index_path.push(&PATCH_INDEX_FILENAME);
+    let mut cp_patches_path: PathBuf = env::current_dir().unwrap();
+    cp_patches_path.push(&PATCHES_DIRNAME);
+    if args.iter().any(|a| a == "--patches") {
+        build_patches(&cp_path, &cp_patches_path, &cp_patch_index_path, &old_hashes, &hashes);
+    }
+
+    // optionally publish gzipped copies of the manifest and of text files, which the updater prefers when they're there
+    let mut cp_compressed_index_path: PathBuf = env::current_dir().unwrap();
+    cp_compressed_index_path.push(&COMPRESSED_INDEX_FILENAME);
+    let mut cp_compressed_path: PathBuf = env::current_dir().unwrap();
+    cp_compressed_path.push(&COMPRESSED_DIRNAME);
+    let cp_manifest_gz_path = PathBuf::from(format!("{}{}", cp_manifest_path.display(), GZIP_SUFFIX));
+    if args.iter().any(|a| a == "--compress") {
+        match File::create(&cp_manifest_gz_path).and_then(|mut f| f.write_all(&compression::gzip(j.as_bytes()))) {
+            Ok(_) => println!("Compressed manifest written out to '{}'.", cp_manifest_gz_path.display()),
+            Err(why) => panic!("Couldn't write to {}: {}", cp_manifest_gz_path.display(), why),
+        }
+        build_compressed_copies(&cp_path, &cp_compressed_path, &cp_compressed_index_path, &hashes);
+    } else {
+        // updaters prefer the compressed manifest, so one left over from an earlier build would keep them on that version for good
+        let _ = std::fs::remove_file(&cp_manifest_gz_path);
+        let _ = std::fs::remove_file(&cp_compressed_index_path);
+        let _ = std::fs::remove_dir_all(&cp_compressed_path);
+    }
+
+    // optionally export every file in the content-addressed layout, so updaters can get files by hash from urls that never change
+    let mut cp_object_index_path: PathBuf = env::current_dir().unwrap();
+    cp_object_index_path.push(&OBJECT_INDEX_FILENAME);
+    let mut cp_objects_path: PathBuf = env::current_dir().unwrap();
+    cp_objects_path.push(&OBJECTS_DIRNAME);
+    if args.iter().any(|a| a == "--objects") {
+        export_objects(&cp_path, &env::current_dir().unwrap(), &cp_object_index_path, &hashes);
+    }
+
+    // give files their update policies, if there's a policy file
+    let mut cp_policy_rules_path: PathBuf = env::current_dir().unwrap();
+    cp_policy_rules_path.push(&POLICY_RULES_FILENAME);
+    let mut cp_policy_index_path: PathBuf = env::current_dir().unwrap();
+    cp_policy_index_path.push(&POLICY_INDEX_FILENAME);
+    if cp_policy_rules_path.exists() {
+        let rules = match policy::load_rules_file(&cp_policy_rules_path) {
+            Ok(r) => r,
+            Err(why) => panic!("{}", why),
+        };
+        let paths = hashes.iter().filter(|&(_, hash)| !utils::is_placeholder_hash(hash)).map(|(path, _)| path);
+        let policy_index = policy::build_index(&rules, paths);
+        match utils::save_json(&cp_policy_index_path, &policy_index, "policy index") {
+            Ok(_) => println!("Update policies for {} files written out to '{}'.", policy_index.len(), cp_policy_index_path.display()),
+            Err(why) => panic!("{}", why),
+        }
+    }
+
+    // group files into optional components, if there's a component file
+    let mut cp_component_rules_path: PathBuf = env::current_dir().unwrap();
+    cp_component_rules_path.push(&COMPONENT_RULES_FILENAME);
+    let mut cp_components_path: PathBuf = env::current_dir().unwrap();
+    cp_components_path.push(&COMPONENTS_FILENAME);
+    if cp_component_rules_path.exists() {
+        let (descriptions, rules) = match components::load_rules_file(&cp_component_rules_path) {
+            Ok(r) => r,
+            Err(why) => panic!("{}", why),
+        };
+        // the version file has to be installed no matter what
+        let paths = hashes.iter().filter(|&(path, hash)| path != VERSION_FILENAME && !utils::is_placeholder_hash(hash)).map(|(path, _)| path);
+        let component_list = components::build(&cp_path, &descriptions, &rules, paths);
+        match utils::save_json(&cp_components_path, &component_list, "component list") {
+            Ok(_) => println!("{} components written out to '{}'.", component_list.len(), cp_components_path.display()),
+            Err(why) => panic!("{}", why),
+        }
+    }
+
+    // if updates are pinned to a commit, that pin won't include this manifest until it's moved, which the post-commit step does
+    let mut cp_ref_path: PathBuf = env::current_dir().unwrap();
+    cp_ref_path.push(&REF_FILENAME);
+    if content_changed && cp_ref_path.exists() {
+        println!("Note: updaters are pinned to the commit in '{}'; the post-commit hook (`update-manifest post-commit`) moves the pin once this is committed, or run `update-manifest pin` yourself.", cp_ref_path.display());
+    }
+
+    // record a changelog entry for the new version, if one was given
+    let mut cp_changelog_path: PathBuf = env::current_dir().unwrap();
+    cp_changelog_path.push(&CHANGELOG_FILENAME);
+    if let Some(entry) = changelog_entry(&args) {
+        if entry.trim().is_empty() {
+            println!("The changelog entry is empty, so it wasn't recorded.");
+        } else if content_changed {
+            let mut str_ver = String::new();
+            File::open(&cp_version_path).and_then(|mut f| f.read_to_string(&mut str_ver)).expect("Can't read back the version file.");
+            let version = Version::from_string(&str_ver);
+            let mut log: changelog::Changelog = match utils::load_json_file(&cp_changelog_path) {
+                Ok(l) => l,
+                Err(why) => panic!("{}", why),
+            };
+            changelog::add_entry(&mut log, &version, &entry);
+            match utils::save_json(&cp_changelog_path, &log, "changelog") {
+                Ok(_) => println!("Changelog entry for version {} written out to '{}'.", version.to_string(), cp_changelog_path.display()),
+                Err(why) => panic!("{}", why),
+            }
+        } else {
+            println!("The version wasn't changed, so the changelog entry wasn't recorded.");
+        }
+    }
     
     // We've now created then written a manifest out to disk, then updated the version
     // Now check to see if this program is being used as a git hook, and if so, add the manifest and version files to git's index (so they automatically get included in commits)
@@ -159,18 +354,25 @@ fn main () {
             };
             let args = env::args().collect::<Vec<String>>();
             if name == "pre-commit" || args.contains(&"pre-commit".to_string()) {
-                // this program is being used as a git hook, which means we should add our previously-generated hash manifest and version files to it's index, so they get automatically committed
-                let git_add_version_status = Command::new("git")
-                    .args(&["add", cp_version_path.to_str().unwrap()])
-                    .status()
-                    .expect("Can't run `git add` to add the version file to git's index");
-                let git_add_manifest_status = Command::new("git")
-                    .args(&["add", cp_manifest_path.to_str().unwrap()])
-                    .status()
-                    .expect("Can't run `git add` to add the hash manifest to git's index");
-                if git_add_version_status.success() == false || git_add_manifest_status.success() == false {
-                    // the code returned by either of the git add commands was non-zero
-                    println!("Can't execute `git add`: this program is being used as a git pre-commit hook, but it's unable to automatically add the manifest and version files to git's index. You will have to do this manually before you commit with the following command: git add {} {}", cp_manifest_path.display(), cp_version_path.display());
+                // this program is being used as a git hook, which means we should add everything it wrote to git's index, so it gets automatically committed
+                // the version file and manifest are always written; everything else only if it's been asked for, and compressed copies are removed when they aren't
+                let written = [&cp_version_path, &cp_manifest_path, &cp_changelog_path, &cp_patch_index_path, &cp_patches_path, &cp_manifest_gz_path, &cp_compressed_index_path, &cp_compressed_path, &cp_object_index_path, &cp_objects_path, &cp_policy_index_path, &cp_components_path];
+                let mut failed: Vec<String> = vec![];
+                for p in written.iter() {
+                    // -A, so files pruned from the patches and compressed copies directories leave the index too; whatever's gone altogether is taken out of it
+                    let git_args: &[&str] = if p.exists() { &["add", "-A", "--"] } else { &["rm", "-r", "-q", "--cached", "--ignore-unmatch", "--"] };
+                    let added = Command::new("git")
+                        .args(git_args)
+                        .arg(p)
+                        .status()
+                        .map(|s| s.success())
+                        .unwrap_or(false);
+                    if !added {
+                        failed.push(p.display().to_string());
+                    }
+                }
+                if !failed.is_empty() {
+                    println!("Can't execute `git add`: this program is being used as a git pre-commit hook, but it's unable to automatically add {} to git's index. You will have to do this manually before you commit with the following command: git add -A {}", failed.join(", "), failed.join(" "));
                     return;
                 } else {
                     // files added
@@ -181,3 +383,464 @@ fn main () {
         Err(why) => panic!("Can't get path to this program:{}", why.description()),
     }
 }
+
+/// Copy every file in `hashes` into the objects directory under `root`, named by it's hash, and record it in the object index
+///
+/// Objects are never removed, since older versions' manifests still refer to them, and a file that's already there (from an older version, or another path with the same contents) isn't written again
+fn export_objects (cp_path: &PathBuf, root: &PathBuf, index_path: &PathBuf, hashes: &BTreeMap<String, String>) {
+    let mut index: objects::ObjectIndex = match utils::load_json_file(index_path) {
+        Ok(i) => i,
+        Err(why) => panic!("{}", why),
+    };
+    let mut written = 0;
+    for (path, hash) in hashes {
+        if utils::is_placeholder_hash(hash) {
+            continue;
+        }
+        let object_path = objects::object_path(root, hash).expect("checksums made a hash that isn't hex");
+        if !object_path.exists() {
+            create_dir_all(object_path.parent().unwrap()).expect("Can't create a directory for objects.");
+            match std::fs::copy(cp_path.join(path), &object_path) {
+                Ok(_) => written += 1,
+                Err(why) => panic!("Couldn't write {}: {}", object_path.display(), why),
+            }
+        }
+        index.insert(hash.clone());
+    }
+    match utils::save_json(index_path, &index, "object index") {
+        Ok(_) => println!("{} new objects written out to '{}'; {} in all.", written, root.join(OBJECTS_DIRNAME).display(), index.len()),
+        Err(why) => panic!("{}", why),
+    }
+}
+
+/// Check every published path against Windows' filename rules, printing a warning for each problem; returns true if there weren't any
+fn lint_paths (hashes: &BTreeMap<String, String>) -> bool {
+    let published: Vec<&String> = hashes.iter().filter(|&(_, hash)| !utils::is_placeholder_hash(hash)).map(|(path, _)| path).collect();
+    let mut clean = true;
+    for path in &published {
+        for problem in paths::portability_problems(path) {
+            println!("Warning: {}: {}", path, problem);
+            clean = false;
+        }
+    }
+    for group in paths::case_collisions(published) {
+        println!("Warning: these paths only differ by case, so only one of them can exist on Windows: {}", group.join(", "));
+        clean = false;
+    }
+    clean
+}
+
+/// Print what changed between two manifests (given as file paths or URLs), for writing release notes
+fn diff_manifests (args: &[String]) {
+    let usage = "Usage: update-manifest diff <old manifest> <new manifest> [--group] [--format text|json|markdown]";
+    let mut locations: Vec<&String> = vec![];
+    let mut group = false;
+    let mut format = DiffFormat::Text;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--group" => group = true,
+            "--format" => {
+                i += 1;
+                format = match args.get(i).and_then(|f| DiffFormat::from_string(f)) {
+                    Some(f) => f,
+                    None => {
+                        println!("{}", usage);
+                        return;
+                    },
+                };
+            },
+            _ => locations.push(&args[i]),
+        }
+        i += 1;
+    }
+    if locations.len() != 2 {
+        println!("{}", usage);
+        return;
+    }
+    let old = match manifest::load(locations[0]) {
+        Ok(m) => m,
+        Err(why) => {
+            println!("{}", why);
+            return;
+        },
+    };
+    let new = match manifest::load(locations[1]) {
+        Ok(m) => m,
+        Err(why) => {
+            println!("{}", why);
+            return;
+        },
+    };
+    match manifest::compare(old, new) {
+        Ok(diff) => print!("{}", diff.format(format, group)),
+        Err(why) => println!("{}", why),
+    }
+}
+
+/// Work out the changelog entry for this build from the arguments, if there is one
+///
+/// `--changelog <text>` uses the text as is, and `--changelog-file <path>` reads it from a file (a CHANGES file, for instance); `--changelog-git` is handled by the post-commit step instead, since the commit message isn't known until then
+fn changelog_entry (args: &[String]) -> Option<String> {
+    if let Some(text) = utils::arg_value(args, "--changelog") {
+        return Some(text);
+    }
+    if let Some(path) = utils::arg_value(args, "--changelog-file") {
+        let mut text = String::new();
+        match File::open(&path).and_then(|mut f| f.read_to_string(&mut text)) {
+            Ok(_) => return Some(text),
+            Err(why) => panic!("Can't read changelog file {}: {}", path, why),
+        }
+    }
+    None
+}
+
+/// Record what the commit that was just made published; this runs as the post-commit hook, after the pre-commit hook has built the manifest
+///
+/// If updates are pinned (there's a ref file), a commit that changed the manifest becomes the pinned commit. With `--changelog-git`, a commit that bumped the version has it's message recorded as that version's changelog entry. Neither can happen until the commit exists, so they go in a commit of their own right after it
+fn post_commit (args: &[String]) {
+    // commits being replayed by a rebase or cherry-pick were published (or not) when they were first made
+    let replaying = ["rebase-merge", "rebase-apply", "CHERRY_PICK_HEAD"].iter()
+        .any(|name| git_output(&["rev-parse", "--git-path", name]).map_or(false, |p| Path::new(&p).exists()));
+    if replaying {
+        return;
+    }
+    let changed = match git_output(&["diff-tree", "--root", "--no-commit-id", "--name-only", "-r", "HEAD"]) {
+        Some(c) => c,
+        None => {
+            println!("Unable to see what the last commit changed; is this a git repository?");
+            return;
+        },
+    };
+    let version_changed = changed.lines().any(|f| f == format!("{}/{}", TARGET_DIR, VERSION_FILENAME));
+    let mut to_commit: Vec<PathBuf> = vec![];
+    let mut summary: Vec<String> = vec![];
+    if version_changed && args.iter().any(|a| a == "--changelog-git") {
+        let message = git_output(&["log", "-1", "--format=%B", "HEAD"]).unwrap_or_default();
+        match version_at("HEAD") {
+            Some(_) if message.trim().is_empty() => println!("The commit message is empty, so no changelog entry was recorded."),
+            Some(version) => {
+                let mut cp_changelog_path: PathBuf = env::current_dir().unwrap();
+                cp_changelog_path.push(&CHANGELOG_FILENAME);
+                let mut log: changelog::Changelog = match utils::load_json_file(&cp_changelog_path) {
+                    Ok(l) => l,
+                    Err(why) => panic!("{}", why),
+                };
+                changelog::add_entry(&mut log, &version, &message);
+                match utils::save_json(&cp_changelog_path, &log, "changelog") {
+                    Ok(_) => println!("Changelog entry for version {} written out to '{}'.", version.to_string(), cp_changelog_path.display()),
+                    Err(why) => panic!("{}", why),
+                }
+                to_commit.push(cp_changelog_path);
+                summary.push(format!("Record the changelog for version {}", version.to_string()));
+            },
+            None => println!("Warning: the last commit doesn't have a readable version file, so no changelog entry was recorded."),
+        }
+    }
+    // every commit that publishes a manifest goes into the version index, so updaters can roll back to it, and it becomes the pinned commit if updates are pinned
+    let mut cp_ref_path: PathBuf = env::current_dir().unwrap();
+    cp_ref_path.push(&REF_FILENAME);
+    if changed.lines().any(|f| f == MANIFEST_FILENAME) {
+        let head = git_output(&["rev-parse", "HEAD"]).unwrap_or_default();
+        if cp_ref_path.exists() {
+            if let Some(written) = record_pin(&head) {
+                println!("Updates pinned to commit {}.", head);
+                to_commit.extend(written);
+                summary.push(format!("Pin updates to {}", &head[..head.len().min(12)]));
+            }
+        } else if let Some(written) = record_version(&head) {
+            println!("Commit {} added to the version index.", head);
+            to_commit.push(written);
+            summary.push(format!("Add {} to the version index", &head[..head.len().min(12)]));
+        }
+    }
+    if to_commit.is_empty() {
+        return;
+    }
+    // this commit doesn't change the manifest, so the hooks have nothing to do for it
+    let mut add = Command::new("git");
+    add.args(&["add", "--"]).args(&to_commit);
+    let mut commit = Command::new("git");
+    commit.args(&["commit", "-q", "--no-verify", "-m", &summary.join("; "), "--"]).args(&to_commit);
+    let committed = [add, commit].iter_mut().all(|c| c.status().map(|s| s.success()).unwrap_or(false));
+    if committed {
+        println!("Committed {}.", to_commit.iter().map(|p| p.display().to_string()).collect::<Vec<String>>().join(", "));
+    } else {
+        println!("Unable to commit {}; commit it yourself before pushing.", to_commit.iter().map(|p| p.display().to_string()).collect::<Vec<String>>().join(", "));
+    }
+}
+
+/// Run git with `args`, returning what it printed (trimmed), or None if it failed
+fn git_output (args: &[&str]) -> Option<String> {
+    let out = Command::new("git").args(args).stderr(Stdio::null()).output().ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+/// Create patches for every large file that changed since the last manifest, using the last committed copy of each file as the base
+fn build_patches (cp_path: &PathBuf, patches_path: &PathBuf, index_path: &PathBuf, old_hashes: &BTreeMap<String, String>, hashes: &BTreeMap<String, String>) {
+    let mut index: patch::PatchIndex = match utils::load_json_file(index_path) {
+        Ok(i) => i,
+        Err(why) => panic!("{}", why),
+    };
+    println!("Creating patches...");
+    let mut created = 0;
+    for (file, new_hash) in hashes {
+        let old_hash = match old_hashes.get(file) {
+            Some(h) if h != new_hash && !utils::is_placeholder_hash(h) && !utils::is_placeholder_hash(new_hash) => h,
+            _ => continue, // new, unchanged, or ignored
+        };
+        let mut new_path = cp_path.clone();
+        new_path.push(file);
+        match new_path.metadata() {
+            Ok(m) if m.len() >= PATCH_MIN_SIZE => (),
+            _ => continue,
+        }
+        if patch::has_patch(&index, old_hash, new_hash) {
+            continue;
+        }
+        // get the previous version of this file out of git
+        let old = Command::new("git")
+            .args(&["show", &format!("HEAD:{}/{}", TARGET_DIR, file)])
+            .output()
+            .expect("Can't run `git show` to get the previous version of a file");
+        if !old.status.success() || &utils::hash_bytes(&old.stdout) != old_hash {
+            println!("Skipping a patch for '{}': the committed copy doesn't match the old manifest.", file);
+            continue;
+        }
+        let mut new: Vec<u8> = vec![];
+        File::open(&new_path).and_then(|mut f| f.read_to_end(&mut new)).expect("Can't read a file to create a patch for it.");
+        let p = match patch::make_patch(&old.stdout, &new) {
+            Ok(p) => p,
+            Err(why) => {
+                println!("Skipping a patch for '{}': {}", file, why);
+                continue;
+            },
+        };
+        // a patch is only useful if it's smaller than just downloading the file
+        if p.len() as u64 >= new.len() as u64 {
+            continue;
+        }
+        let out = patch::patch_path(patches_path, old_hash, new_hash);
+        create_dir_all(out.parent().unwrap()).expect("Can't create the patches directory.");
+        match File::create(&out).and_then(|mut f| f.write_all(&p)) {
+            Ok(_) => (),
+            Err(why) => panic!("Couldn't write patch {}: {}", out.display(), why),
+        }
+        index.entry(new_hash.clone()).or_insert_with(|| patch::PatchTarget {size: new.len() as u64, bases: BTreeSet::new()}).bases.insert(old_hash.clone());
+        created += 1;
+    }
+    // patches to versions of files that aren't in the manifest anymore will never be used again
+    let wanted: BTreeSet<&String> = hashes.values().collect();
+    patch::prune(patches_path, &mut index, &wanted);
+    match utils::save_json(index_path, &index, "patch index") {
+        Ok(_) => println!("{} patches created; patch index written out to '{}'.", created, index_path.display()),
+        Err(why) => panic!("{}", why),
+    }
+}
+
+/// Write gzipped copies of compressible (text) files under `compressed_path`, skipping ones that are already up to date and removing ones that aren't in the manifest anymore
+fn build_compressed_copies (cp_path: &PathBuf, compressed_path: &PathBuf, index_path: &PathBuf, hashes: &BTreeMap<String, String>) {
+    let old_index: compression::CompressedIndex = match utils::load_json_file(index_path) {
+        Ok(i) => i,
+        Err(why) => panic!("{}", why),
+    };
+    let mut index = compression::CompressedIndex::new();
+    for (file, hash) in hashes {
+        if !compression::is_compressible(file) || utils::is_placeholder_hash(hash) {
+            continue;
+        }
+        let out = compressed_path.join(format!("{}{}", file, GZIP_SUFFIX));
+        if old_index.get(file) == Some(hash) && out.exists() {
+            index.insert(file.clone(), hash.clone());
+            continue;
+        }
+        let mut contents: Vec<u8> = vec![];
+        File::open(cp_path.join(file)).and_then(|mut f| f.read_to_end(&mut contents)).expect("Can't read a file to compress it.");
+        let gz = compression::gzip(&contents);
+        // not everything with a text extension actually compresses
+        if gz.len() >= contents.len() {
+            let _ = std::fs::remove_file(&out);
+            continue;
+        }
+        create_dir_all(out.parent().unwrap()).expect("Can't create the compressed copies directory.");
+        match File::create(&out).and_then(|mut f| f.write_all(&gz)) {
+            Ok(_) => (),
+            Err(why) => panic!("Couldn't write compressed copy {}: {}", out.display(), why),
+        }
+        index.insert(file.clone(), hash.clone());
+    }
+    for file in old_index.keys().filter(|f| !index.contains_key(*f)) {
+        let _ = std::fs::remove_file(compressed_path.join(format!("{}{}", file, GZIP_SUFFIX)));
+    }
+    match utils::save_json(index_path, &index, "compressed index") {
+        Ok(_) => println!("{} compressed copies listed in '{}'.", index.len(), index_path.display()),
+        Err(why) => panic!("{}", why),
+    }
+}
+
+/// Write the commit updaters should fetch the manifest and every file from (HEAD, unless one is given) to the ref file
+fn pin (commit: Option<&String>) {
+    let commit = commit.map_or("HEAD", |c| c.trim());
+    // pin the full id of a commit that's really here, rather than whatever was typed
+    let commit = match git_output(&["rev-parse", "--verify", "-q", &format!("{}^{{commit}}", commit)]) {
+        Some(id) => id,
+        None => {
+            println!("'{}' isn't a commit in this repository.", commit);
+            return;
+        },
+    };
+    if let Some(written) = record_pin(&commit) {
+        println!("Updates pinned to commit {}; commit and push {} to publish it.", commit, written.iter().map(|p| format!("'{}'", p.display())).collect::<Vec<String>>().join(", "));
+    }
+}
+
+/// Write `commit` to the ref file, and add it to the version index; returns the files written, or None (having said why) if it can't be pinned
+fn record_pin (commit: &str) -> Option<Vec<PathBuf>> {
+    if !source::is_commit_id(commit) {
+        println!("'{}' isn't a commit id.", commit);
+        return None;
+    }
+    // the pinned commit has to have a manifest of it's own, since that's what updaters will compare against
+    let has_manifest = Command::new("git")
+        .args(&["cat-file", "-e", &format!("{}:{}", commit, MANIFEST_FILENAME)])
+        .stderr(Stdio::null())
+        .status()
+        .expect("Can't run `git cat-file` to check the commit has a manifest");
+    if !has_manifest.success() {
+        println!("Commit {} doesn't contain {}; build and commit a manifest first.", commit, MANIFEST_FILENAME);
+        return None;
+    }
+    let mut cp_ref_path: PathBuf = env::current_dir().unwrap();
+    cp_ref_path.push(&REF_FILENAME);
+    match File::create(&cp_ref_path).and_then(|mut f| f.write_all(commit.as_bytes())) {
+        Ok(_) => (),
+        Err(why) => panic!("Couldn't write to {}: {}", cp_ref_path.display(), why),
+    }
+    let mut written = vec![cp_ref_path];
+    // add the commit to the version index too, so updaters can roll back to it later
+    written.extend(record_version(commit));
+    Some(written)
+}
+
+/// Add `commit` to the version index under the version it publishes; returns the index file if it was written
+fn record_version (commit: &str) -> Option<PathBuf> {
+    let mut cp_versions_path: PathBuf = env::current_dir().unwrap();
+    cp_versions_path.push(&VERSIONS_FILENAME);
+    let mut index: versions::VersionIndex = match utils::load_json_file(&cp_versions_path) {
+        Ok(i) => i,
+        Err(why) => panic!("{}", why),
+    };
+    match version_at(commit) {
+        Some(v) => {
+            index.insert(v.to_string(), commit.to_string());
+            if let Err(why) = utils::save_json(&cp_versions_path, &index, "version index") {
+                panic!("{}", why);
+            }
+            Some(cp_versions_path)
+        },
+        None => {
+            println!("Warning: commit {} doesn't have a readable version file, so it wasn't added to the version index.", commit);
+            None
+        },
+    }
+}
+
+/// Rebuild the version index from every commit that changed the manifest
+fn rebuild_versions () {
+    let index = match GitRepo::local(Path::new(".")).version_index("HEAD") {
+        Ok(i) => i,
+        Err(why) => {
+            println!("Unable to read the manifest's history; is this a git repository? {}", why);
+            return;
+        },
+    };
+    let mut cp_versions_path: PathBuf = env::current_dir().unwrap();
+    cp_versions_path.push(&VERSIONS_FILENAME);
+    match utils::save_json(&cp_versions_path, &index, "version index") {
+        Ok(_) => println!("{} versions written out to '{}'.", index.len(), cp_versions_path.display()),
+        Err(why) => panic!("{}", why),
+    }
+}
+
+/// Package the manifest, and every file that's changed since version `from`, into a bundle updaters can apply offline
+fn make_bundle (from: &str, output: Option<String>) {
+    let root: PathBuf = env::current_dir().unwrap();
+    let from = match Version::parse(from) {
+        Ok(v) => v,
+        Err(why) => {
+            println!("{}", why);
+            return;
+        },
+    };
+    let to = match std::fs::read_to_string(root.join(TARGET_DIR).join(VERSION_FILENAME)).map_err(|why| why.to_string()).and_then(|s| Version::parse(&s)) {
+        Ok(v) => v,
+        Err(why) => {
+            println!("Can't read the current version from {}: {}", VERSION_FILENAME, why);
+            return;
+        },
+    };
+    let manifest_path = root.join(MANIFEST_FILENAME);
+    let hashes: BTreeMap<String, String> = match manifest::load_file(&manifest_path) {
+        Ok(m) => m,
+        Err(why) => {
+            println!("Can't read the manifest; build it first. {}", why);
+            return;
+        },
+    };
+    // the old manifest comes from the commit the version index says `from` was published at
+    let index: versions::VersionIndex = match utils::load_json_file(&root.join(VERSIONS_FILENAME)) {
+        Ok(i) => i,
+        Err(why) => panic!("{}", why),
+    };
+    let commit = match versions::commit_for(&index, &from) {
+        Some(c) => c,
+        None => {
+            println!("Version {} isn't in {}; run `update-manifest versions` to rebuild it from git history.", from.to_string(), VERSIONS_FILENAME);
+            return;
+        },
+    };
+    let old = Command::new("git")
+        .args(&["show", &format!("{}:{}", commit, MANIFEST_FILENAME)])
+        .stderr(Stdio::null())
+        .output()
+        .expect("Can't run `git show` to read the old manifest");
+    let old_hashes: BTreeMap<String, String> = match serde_json::from_slice(&old.stdout) {
+        Ok(m) if old.status.success() => manifest::canonicalize(m),
+        _ => {
+            println!("Can't read the manifest of version {} from commit {}.", from.to_string(), commit);
+            return;
+        },
+    };
+    let mut files = bundle::changed_files(&old_hashes, &hashes);
+    if !files.iter().any(|f| f == VERSION_FILENAME) {
+        files.push(VERSION_FILENAME.to_string());
+    }
+    // the bundle has to hold exactly what the manifest describes, since that's what updaters check it against
+    let cp_path = root.join(TARGET_DIR);
+    for file in &files {
+        if let Some(expected) = hashes.get(file) {
+            if !cp_path.join(file).is_file() {
+                println!("{} is in the manifest, but it doesn't exist; rebuild the manifest before making a bundle.", file);
+                return;
+            }
+            if &checksums::hash_file(&cp_path.join(file), ALGO) != expected {
+                println!("{} doesn't match the manifest; rebuild the manifest before making a bundle.", file);
+                return;
+            }
+        }
+    }
+    let output = output.map(PathBuf::from)
+        .unwrap_or_else(|| root.join(format!("{}-{}-to-{}{}", TARGET_DIR, from.to_string(), to.to_string(), BUNDLE_SUFFIX)));
+    match bundle::create(&output, &root, &from.to_string(), &to.to_string(), &files) {
+        Ok(_) => println!("{} files changed since version {} written out to '{}', with it's checksum in '{}'.", files.len(), from.to_string(), output.display(), bundle::checksum_path(&output).display()),
+        Err(why) => panic!("{}", why),
+    }
+}
+
+/// Read chatpack's version as of `commit`
+fn version_at (commit: &str) -> Option<Version> {
+    GitRepo::local(Path::new(".")).version_at(commit)
+}
@@ -0,0 +1,235 @@
+// offline update bundles: the manifest and every file changed since some version, in one gzipped tar archive, for players who can't update online
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use crate::constants::*;
+use crate::paths;
+use crate::utils;
+
+/// What a bundle says about itself
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleInfo {
+    pub from: String, // the version the bundle updates from; it only holds files that changed since then
+    pub to: String, // the version it updates to
+    pub contents: BTreeMap<String, String>, // every other file in the bundle, with it's hash, so the bundle can be checked as a whole without the checksum file next to it
+}
+
+/// The file holding the checksum of the bundle at `bundle`
+pub fn checksum_path (bundle: &Path) -> PathBuf {
+    let mut name = bundle.file_name().unwrap().to_os_string();
+    name.push(BUNDLE_CHECKSUM_SUFFIX);
+    bundle.with_file_name(name)
+}
+
+/// Write a bundle updating version `from` to `to` to `output`, then it's checksum next to it
+///
+/// `root` is the directory holding the manifest (and the extras published next to it); `files` are the paths, relative to `TARGET_DIR` under it, of the files to include
+pub fn create (output: &Path, root: &Path, from: &str, to: &str, files: &[String]) -> Result<(), String> {
+    // the info goes first, so it's list of contents is known before anything else is unpacked
+    let mut contents = BTreeMap::new();
+    let names = std::iter::once(MANIFEST_FILENAME.to_string())
+        .chain(BUNDLED_EXTRAS.iter().filter(|name| root.join(name).exists()).map(|name| name.to_string()))
+        .chain(files.iter().map(|file| format!("{}/{}", TARGET_DIR, file)));
+    for name in names {
+        let bytes = fs::read(root.join(&name)).map_err(|why| format!("Can't read {}: {}", name, why))?;
+        contents.insert(name, utils::hash_bytes(&bytes));
+    }
+    let info = BundleInfo {from: from.to_string(), to: to.to_string(), contents};
+    let f = File::create(output).map_err(|why| format!("couldn't create or open {}: {}", output.display(), why))?;
+    let mut archive = tar::Builder::new(GzEncoder::new(f, Compression::best()));
+    let j = serde_json::to_vec_pretty(&info).map_err(|why| format!("Couldn't create a json representation of the bundle info: {}", why))?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(j.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    archive.append_data(&mut header, BUNDLE_INFO_FILENAME, &j[..]).map_err(|why| format!("Couldn't write to {}: {}", output.display(), why))?;
+    archive.append_path_with_name(root.join(MANIFEST_FILENAME), MANIFEST_FILENAME).map_err(|why| format!("Couldn't add the manifest to {}: {}", output.display(), why))?;
+    for name in BUNDLED_EXTRAS.iter() {
+        if root.join(name).exists() {
+            archive.append_path_with_name(root.join(name), name).map_err(|why| format!("Couldn't add {} to {}: {}", name, output.display(), why))?;
+        }
+    }
+    let cp_path = root.join(TARGET_DIR);
+    for file in files {
+        archive.append_path_with_name(cp_path.join(file), format!("{}/{}", TARGET_DIR, file)).map_err(|why| format!("Couldn't add {} to {}: {}", file, output.display(), why))?;
+    }
+    archive.into_inner().and_then(|gz| gz.finish()).map_err(|why| format!("Couldn't write to {}: {}", output.display(), why))?;
+    let checksum = checksums::hash_file(output, ALGO);
+    fs::write(checksum_path(output), &checksum).map_err(|why| format!("Couldn't write {}'s checksum: {}", output.display(), why))
+}
+
+/// Check `bundle` against the checksum next to it; returns false if there isn't one to check against
+pub fn verify_checksum (bundle: &Path) -> Result<bool, String> {
+    if !bundle.is_file() {
+        return Err(format!("Can't open '{}': there's no such file", bundle.display()));
+    }
+    let expected = match fs::read_to_string(checksum_path(bundle)) {
+        Ok(c) => c,
+        Err(_) => return Ok(false),
+    };
+    if checksums::hash_file(bundle, ALGO) != expected.trim() {
+        return Err(format!("{} doesn't match it's checksum; it may be damaged or incomplete", bundle.display()));
+    }
+    Ok(true)
+}
+
+/// Unpack `bundle` into `dest`, returning what it says about itself; anything in it that isn't part of a bundle (or would be written outside `dest`) is left out
+///
+/// Every file the bundle lists has to be there, and match the hash it lists, or the bundle is damaged or incomplete
+pub fn unpack (bundle: &Path, dest: &Path) -> Result<BundleInfo, String> {
+    let f = File::open(bundle).map_err(|why| format!("Can't open '{}': {}", bundle.display(), why))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(f));
+    let entries = archive.entries().map_err(|why| format!("Can't read '{}': {}", bundle.display(), why))?;
+    let mut info: Option<BundleInfo> = None;
+    let mut unpacked = BTreeSet::new();
+    for entry in entries {
+        let mut entry = entry.map_err(|why| format!("Can't read '{}': {}", bundle.display(), why))?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = match entry.path() {
+            Ok(p) => p.to_string_lossy().into_owned(),
+            Err(_) => continue,
+        };
+        if name == BUNDLE_INFO_FILENAME {
+            let mut j = String::new();
+            entry.read_to_string(&mut j).map_err(|why| format!("Can't read '{}': {}", bundle.display(), why))?;
+            info = Some(serde_json::from_str::<BundleInfo>(&j).map_err(|why| format!("Error parsing the bundle info in '{}': {}", bundle.display(), why))?);
+            continue;
+        }
+        // the info comes first, so anything before it isn't part of a bundle
+        let expected_hash = match info {
+            Some(ref i) => match i.contents.get(&name) {
+                Some(h) => h.clone(),
+                None => continue,
+            },
+            None => return Err(format!("'{}' isn't an update bundle", bundle.display())),
+        };
+        let wanted = name == MANIFEST_FILENAME || BUNDLED_EXTRAS.contains(&name.as_str())
+            || name.strip_prefix(&format!("{}/", TARGET_DIR)).map_or(false, |file| paths::unsafe_reason(file).is_none());
+        if !wanted {
+            continue;
+        }
+        let p = dest.join(&name);
+        fs::create_dir_all(p.parent().unwrap()).map_err(|why| why.to_string())?;
+        entry.unpack(&p).map_err(|why| format!("Can't unpack {} from '{}': {}", name, bundle.display(), why))?;
+        let bytes = fs::read(&p).map_err(|why| format!("Can't read {} back: {}", p.display(), why))?;
+        if utils::hash_bytes(&bytes) != expected_hash {
+            return Err(format!("{} in '{}' doesn't match it's hash; the bundle is damaged", name, bundle.display()));
+        }
+        unpacked.insert(name);
+    }
+    let info = info.ok_or_else(|| format!("'{}' isn't an update bundle", bundle.display()))?;
+    let missing: Vec<&String> = info.contents.keys().filter(|name| !unpacked.contains(*name)).collect();
+    if !missing.is_empty() {
+        return Err(format!("'{}' is incomplete; it's missing {}", bundle.display(), missing.iter().map(|n| n.as_str()).collect::<Vec<&str>>().join(", ")));
+    }
+    Ok(info)
+}
+
+/// The files a bundle from a version whose manifest was `old` to one whose manifest is `new` has to hold: everything new or changed since
+pub fn changed_files (old: &BTreeMap<String, String>, new: &BTreeMap<String, String>) -> Vec<String> {
+    new.iter()
+        .filter(|&(path, hash)| !utils::is_placeholder_hash(hash) && old.get(path) != Some(hash))
+        .map(|(path, _)| path.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Add a file called `name` to `archive`, writing the name straight into the header so names tar would refuse can be tested
+    fn append_raw (archive: &mut tar::Builder<GzEncoder<File>>, name: &str, contents: &[u8]) {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_cksum();
+        archive.append(&header, contents).unwrap();
+    }
+
+    /// Write a bundle to `path` holding `entries` after it's info, which lists those in `listed` (by name, with the hash of the contents given)
+    fn write_bundle (path: &Path, entries: &[(&str, &[u8])], listed: &[(&str, &[u8])]) {
+        let contents = listed.iter().map(|&(name, bytes)| (name.to_string(), utils::hash_bytes(bytes))).collect();
+        let info = BundleInfo {from: "1.0".to_string(), to: "1.1".to_string(), contents};
+        let mut archive = tar::Builder::new(GzEncoder::new(File::create(path).unwrap(), Compression::default()));
+        append_raw(&mut archive, BUNDLE_INFO_FILENAME, &serde_json::to_vec(&info).unwrap());
+        for &(name, bytes) in entries {
+            append_raw(&mut archive, name, bytes);
+        }
+        archive.into_inner().unwrap().finish().unwrap();
+    }
+
+    /// A fresh directory to unpack into, under one to write the bundle to
+    fn test_dir (name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("chatpack-bundle-test-{}-{}", name, std::process::id()));
+        let dest = dir.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        (dir, dest)
+    }
+
+    #[test]
+    fn unpacking_leaves_out_what_isnt_part_of_a_bundle () {
+        let (dir, dest) = test_dir("unpack");
+        let bundle = dir.join("test.update-bundle.tar.gz");
+        let listed: &[(&str, &[u8])] = &[(MANIFEST_FILENAME, b"{}"), ("chatpack/lib/file.lua", b"wanted")];
+        let mut entries = listed.to_vec();
+        entries.extend_from_slice(&[
+            ("chatpack/../escape.lua", b"unwanted"), ("../escape.lua", b"unwanted"), ("chatpack/C:/escape.lua", b"unwanted"),
+            ("chatpack/aux.lua", b"unwanted"), ("stray.txt", b"unwanted"),
+        ]);
+        write_bundle(&bundle, &entries, listed);
+
+        let info = unpack(&bundle, &dest).unwrap();
+        assert_eq!((info.from.as_str(), info.to.as_str()), ("1.0", "1.1"));
+        assert_eq!(fs::read(dest.join("chatpack/lib/file.lua")).unwrap(), b"wanted");
+        assert!(dest.join(MANIFEST_FILENAME).exists());
+        assert!(!dest.join("escape.lua").exists());
+        assert!(!dir.join("escape.lua").exists());
+        assert!(!dest.join("chatpack/aux.lua").exists());
+        assert!(!dest.join("stray.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bundles_listing_unsafe_paths_are_refused () {
+        let (dir, dest) = test_dir("unsafe");
+        let bundle = dir.join("test.update-bundle.tar.gz");
+        let entries: &[(&str, &[u8])] = &[(MANIFEST_FILENAME, b"{}"), ("chatpack/../escape.lua", b"unwanted")];
+        write_bundle(&bundle, entries, entries);
+        assert!(unpack(&bundle, &dest).is_err());
+        assert!(!dest.join("escape.lua").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_or_incomplete_bundles_are_refused () {
+        let (dir, dest) = test_dir("damaged");
+        let bundle = dir.join("test.update-bundle.tar.gz");
+        write_bundle(&bundle, &[(MANIFEST_FILENAME, b"{}"), ("chatpack/file.lua", b"damaged")], &[(MANIFEST_FILENAME, b"{}"), ("chatpack/file.lua", b"contents")]);
+        assert!(unpack(&bundle, &dest).is_err());
+        write_bundle(&bundle, &[(MANIFEST_FILENAME, b"{}")], &[(MANIFEST_FILENAME, b"{}"), ("chatpack/file.lua", b"contents")]);
+        assert!(unpack(&bundle, &dest).is_err());
+        write_bundle(&bundle, &[(MANIFEST_FILENAME, b"{}"), ("chatpack/file.lua", b"contents")], &[(MANIFEST_FILENAME, b"{}"), ("chatpack/file.lua", b"contents")]);
+        assert!(unpack(&bundle, &dest).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bundles_hold_new_and_changed_files_only () {
+        let old: BTreeMap<String, String> = vec![
+            ("same.lua", "aaaa"), ("changed.lua", "bbbb"), ("removed.lua", "cccc"),
+        ].into_iter().map(|(p, h)| (p.to_string(), h.to_string())).collect();
+        let new: BTreeMap<String, String> = vec![
+            ("same.lua", "aaaa"), ("changed.lua", "dddd"), ("added.lua", "eeee"), ("ignored.lua", "----"),
+        ].into_iter().map(|(p, h)| (p.to_string(), h.to_string())).collect();
+        assert_eq!(changed_files(&old, &new), vec!["added.lua".to_string(), "changed.lua".to_string()]);
+    }
+}
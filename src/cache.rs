@@ -0,0 +1,111 @@
+// the shared content cache: files the updater has downloaded, stored by hash in a per-user directory, so other mush folders on the same computer don't have to download them again
+
+use std::env;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use walkdir::WalkDir;
+use crate::constants::{CACHE_DIRNAME, DEFAULT_CACHE_LIMIT_MB};
+use crate::objects;
+use crate::settings::Settings;
+
+pub struct Cache {
+    pub dir: PathBuf,
+    pub limit: u64, // the most the cache should hold, in bytes
+}
+
+/// Where the cache goes by default: under the user's local app data folder on Windows, and their cache directory elsewhere
+pub fn default_dir () -> Option<PathBuf> {
+    let base = env::var_os("LOCALAPPDATA").map(PathBuf::from)
+        .or_else(|| env::var_os("XDG_CACHE_HOME").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".cache")))?;
+    Some(base.join(CACHE_DIRNAME))
+}
+
+impl Cache {
+    /// The cache as `settings` describe it, whether or not it's turned on; None if there's nowhere to put it
+    pub fn from_settings(settings: &Settings) -> Option<Cache> {
+        let dir = match settings.cache_dir {
+            Some(ref d) => PathBuf::from(d),
+            None => default_dir()?,
+        };
+        let limit = settings.cache_limit.unwrap_or(DEFAULT_CACHE_LIMIT_MB) * 1024 * 1024;
+        Some(Cache {dir, limit})
+    }
+
+    /// The cache, if the install at `cp_path` has it turned on
+    pub fn for_install(cp_path: &Path) -> Option<Cache> {
+        let settings = Settings::load(cp_path);
+        if !settings.cache {
+            return None;
+        }
+        Cache::from_settings(&settings)
+    }
+
+    /// Where the file with the given hash is kept; None if `hash` isn't one, so nothing outside the cache is ever touched
+    pub fn path(&self, hash: &str) -> Option<PathBuf> {
+        objects::object_name(hash).map(|name| self.dir.join(name))
+    }
+
+    /// Copy the file with the given hash out of the cache to `dest`; it's up to the caller to check the copy, since the cache can be changed by anyone
+    pub fn copy_to(&self, hash: &str, dest: &Path) -> Result<(), String> {
+        let p = self.path(hash).ok_or_else(|| format!("'{}' isn't a hash", hash))?;
+        fs::copy(&p, dest).map_err(|why| why.to_string())?;
+        // pruning removes the least recently used files first, so mark this one as used
+        let _ = File::options().write(true).open(&p).and_then(|f| f.set_modified(SystemTime::now()));
+        Ok(())
+    }
+
+    /// Add `src`, which hashes to `hash`, to the cache
+    pub fn store(&self, src: &Path, hash: &str) -> Result<(), String> {
+        let p = self.path(hash).ok_or_else(|| format!("'{}' isn't a hash", hash))?;
+        if p.exists() {
+            return Ok(());
+        }
+        fs::create_dir_all(p.parent().unwrap()).map_err(|why| format!("Can't create {}: {}", p.parent().unwrap().display(), why))?;
+        // copy to a temporary name first, so other updaters never see half a file
+        let part = p.with_extension(format!("{}.part", std::process::id()));
+        if let Err(why) = fs::copy(src, &part).and_then(|_| fs::rename(&part, &p)) {
+            let _ = fs::remove_file(&part);
+            return Err(format!("Can't add {} to the cache: {}", src.display(), why));
+        }
+        Ok(())
+    }
+
+    /// Every file in the cache, with it's size and when it was last used
+    fn entries(&self) -> Vec<(PathBuf, u64, SystemTime)> {
+        WalkDir::new(&self.dir).into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .filter_map(|e| {
+                let m = e.metadata().ok()?;
+                Some((e.path().to_path_buf(), m.len(), m.modified().unwrap_or(SystemTime::UNIX_EPOCH)))
+            })
+            .collect()
+    }
+
+    /// The number of files in the cache, and their total size in bytes
+    pub fn usage(&self) -> (usize, u64) {
+        let entries = self.entries();
+        (entries.len(), entries.iter().map(|&(_, size, _)| size).sum())
+    }
+
+    /// Remove the least recently used files until the cache is no bigger than it's limit, returning how many files were removed and how many bytes that freed
+    pub fn prune(&self) -> (usize, u64) {
+        let mut entries = self.entries();
+        let mut total: u64 = entries.iter().map(|&(_, size, _)| size).sum();
+        entries.sort_by_key(|&(_, _, used)| used);
+        let mut removed = (0, 0);
+        for (p, size, _) in entries {
+            if total <= self.limit {
+                break;
+            }
+            if fs::remove_file(&p).is_ok() {
+                total -= size;
+                removed.0 += 1;
+                removed.1 += size;
+            }
+        }
+        removed
+    }
+}
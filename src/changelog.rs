@@ -0,0 +1,50 @@
+// changelog entries published alongside the manifest, keyed by version
+
+use std::collections::BTreeMap;
+use crate::utils;
+use crate::version::Version;
+
+/// A changelog: version strings mapped to what changed in that version
+pub type Changelog = BTreeMap<String, String>;
+
+/// Load a changelog from either a local file or a URL
+pub fn load (location: &str) -> Result<Changelog, String> {
+    utils::load_json(location)
+}
+
+/// Record `entry` for `version`; if that version already has an entry, the new text is added after it
+pub fn add_entry (changelog: &mut Changelog, version: &Version, entry: &str) {
+    let entry = entry.trim();
+    if entry.is_empty() {
+        return;
+    }
+    let text = changelog.entry(version.to_string()).or_insert_with(String::new);
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    text.push_str(entry);
+}
+
+/// Return the entries newer than `from` (or all of them, if there's no starting version) up to and including `to`, oldest first
+pub fn entries_between (changelog: &Changelog, from: Option<&Version>, to: &Version) -> Vec<(Version, String)> {
+    let mut entries: Vec<(Version, String)> = changelog.iter()
+        // skip keys that aren't valid versions rather than failing the whole update over them
+        .filter_map(|(v, text)| Version::parse(v).ok().map(|v| (v, text.clone())))
+        .filter(|&(ref v, _)| v <= to && from.map_or(true, |from| v > from))
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries
+}
+
+/// Render changelog entries as plain text, one section per version
+pub fn format_entries (entries: &[(Version, String)]) -> String {
+    let mut out = String::new();
+    for &(ref version, ref text) in entries {
+        out.push_str(&format!("Version {}:\n", version.to_string()));
+        for line in text.lines() {
+            out.push_str(&format!("  {}\n", line));
+        }
+        out.push('\n');
+    }
+    out
+}
@@ -0,0 +1,98 @@
+// optional components: named groups of files (sound sets, plugins and so on) users can choose not to install
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::Path;
+use crate::utils::{self, PathPattern};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Component {
+    pub description: String,
+    pub size: u64, // total size of the component's files, in bytes
+    pub files: BTreeSet<String>,
+}
+
+/// Every optional component, by name; files that aren't in a component are always installed
+pub type Components = BTreeMap<String, Component>;
+
+/// One line of a component file: an ignore-style pattern, and the component it puts files in
+pub struct Rule {
+    pattern: PathPattern,
+    component: String,
+}
+
+/// Parse a component file, returning each component's description along with the rules
+///
+/// Component files use ignore file syntax, split into sections by `[name] description` headers; a path belongs to the component of the last pattern that matches it, and a negated pattern takes it back out
+pub fn parse_rules (text: &str) -> Result<(BTreeMap<String, String>, Vec<Rule>), String> {
+    let mut descriptions = BTreeMap::new();
+    let mut rules = vec![];
+    for section in utils::parse_pattern_sections(text)? {
+        if section.header.is_empty() || section.header.contains(char::is_whitespace) {
+            return Err(format!("component names can't be empty or contain spaces: [{}]", section.header));
+        }
+        descriptions.insert(section.header.clone(), section.rest);
+        let component = section.header;
+        rules.extend(section.patterns.into_iter().map(|pattern| Rule {pattern, component: component.clone()}));
+    }
+    Ok((descriptions, rules))
+}
+
+/// Load and parse a component file from disk
+pub fn load_rules_file (path: &Path) -> Result<(BTreeMap<String, String>, Vec<Rule>), String> {
+    let mut text = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut text)).map_err(|why| format!("Can't read {}: {}", path.display(), why))?;
+    parse_rules(&text).map_err(|why| format!("{}: {}", path.display(), why))
+}
+
+/// Sort `paths` (relative to `cp_path`) into components according to `rules`, adding up each component's size
+pub fn build<'a, I: IntoIterator<Item = &'a String>> (cp_path: &Path, descriptions: &BTreeMap<String, String>, rules: &[Rule], paths: I) -> Components {
+    let mut components: Components = descriptions.iter()
+        .map(|(name, description)| (name.clone(), Component {description: description.clone(), ..Component::default()}))
+        .collect();
+    for path in paths {
+        let mut belongs_to = None;
+        for rule in rules {
+            if rule.pattern.matches(path) {
+                belongs_to = if rule.pattern.negation { None } else { Some(&rule.component) };
+            }
+        }
+        if let Some(name) = belongs_to {
+            let component = components.get_mut(name).unwrap();
+            component.size += fs::metadata(cp_path.join(path)).map(|m| m.len()).unwrap_or(0);
+            component.files.insert(path.clone());
+        }
+    }
+    components
+}
+
+/// Load the component list from either a local file or a URL; a pack that doesn't publish one has no components, which isn't an error
+pub fn load (location: &str) -> Result<Components, String> {
+    utils::load_optional_json(location).map(Option::unwrap_or_default)
+}
+
+/// Every file belonging to one of the `disabled` components
+pub fn disabled_files (components: &Components, disabled: &BTreeSet<String>) -> BTreeSet<String> {
+    components.iter()
+        .filter(|&(name, _)| disabled.contains(name))
+        .flat_map(|(_, component)| component.files.iter().cloned())
+        .collect()
+}
+
+/// Format a size in bytes for people to read
+pub fn format_size (bytes: u64) -> String {
+    let units = ["bytes", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
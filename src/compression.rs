@@ -0,0 +1,70 @@
+// compressed transfers: http content-encoding, and pre-compressed copies of the manifest and text files
+
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::path::Path;
+use flate2::Compression;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::GzEncoder;
+use reqwest::header::{AcceptEncoding, ContentEncoding, Encoding, Headers, qitem};
+use crate::constants::COMPRESSIBLE_EXTENSIONS;
+use crate::utils;
+
+/// The compressed index: paths that have a pre-compressed copy, mapped to the hash of the (uncompressed) file the copy was made from
+pub type CompressedIndex = BTreeMap<String, String>;
+
+/// Return an http client that asks for gzip or deflate compressed responses
+///
+/// Bodies should be read through `decoded_body`, which undoes whichever of those the server used
+pub fn http_client () -> reqwest::Client {
+    let mut headers = Headers::new();
+    headers.set(AcceptEncoding(vec![qitem(Encoding::Gzip), qitem(Encoding::Deflate)]));
+    reqwest::Client::builder()
+        .gzip(false) // we handle decoding ourselves so deflate works too
+        .default_headers(headers)
+        .build()
+        .expect("Unable to create an http client.")
+}
+
+/// Wrap a response so reading from it gives the decompressed body, according to it's content-encoding
+pub fn decoded_body (resp: reqwest::Response) -> Box<dyn Read> {
+    let encoding = resp.headers().get::<ContentEncoding>().and_then(|e| e.last().cloned());
+    match encoding {
+        Some(Encoding::Gzip) => Box::new(GzDecoder::new(resp)),
+        Some(Encoding::Deflate) => Box::new(ZlibDecoder::new(resp)),
+        _ => Box::new(resp),
+    }
+}
+
+/// Wrap a reader so reading from it gives the decompressed contents of the gzipped data it holds
+pub fn gunzip_reader (reader: Box<dyn Read>) -> Box<dyn Read> {
+    Box::new(GzDecoder::new(reader))
+}
+
+/// Gzip a buffer
+pub fn gzip (bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(bytes).expect("Can't compress data in memory.");
+    encoder.finish().expect("Can't compress data in memory.")
+}
+
+/// Decompress a gzipped buffer
+pub fn gunzip (bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = vec![];
+    GzDecoder::new(Cursor::new(bytes)).read_to_end(&mut out).map_err(|why| format!("Can't decompress: {}", why))?;
+    Ok(out)
+}
+
+/// Returns true if the file at `path` is the kind of file (lua, xml and other text) that's worth publishing a compressed copy of
+pub fn is_compressible (path: &str) -> bool {
+    match Path::new(path).extension().and_then(|e| e.to_str()) {
+        Some(ext) => COMPRESSIBLE_EXTENSIONS.contains(&ext.to_lowercase().as_str()),
+        None => false,
+    }
+}
+
+/// Load a compressed index from either a local file or a URL
+pub fn load_index (location: &str) -> Result<CompressedIndex, String> {
+    utils::load_json(location)
+}
@@ -4,10 +4,47 @@ use checksums::Algorithm;
 
 pub const TARGET_DIR: &str = "chatpack";
 pub const ALGO: Algorithm = Algorithm::BLAKE2;
+pub const HASH_LENGTH: usize = 128; // the length of a hash made with ALGO, in hex digits
 pub const JOBS: usize = 2;
 pub const VERSION_FILENAME :&str = "chatpack.ver"; // the name of the file (under target_dir) which holds chatpack's current version (and which needs to be updated by this program)
 pub const MANIFEST_FILENAME: &str = "chatpack.update-manifest"; // The filename which contains the hash manifest (which this program will download and compare against)
-pub const MASTER_MANIFEST_URL: &str = "https://git.chatmud.com/athlon/chatpack/raw/master/chatpack.update-manifest";
+pub const REPO_RAW_URL: &str = "https://git.chatmud.com/athlon/chatpack/raw/"; // base url for raw files in chatpack's repository; a ref (branch or commit) and then a path get added to it
+pub const DEFAULT_REF: &str = "master"; // the branch updates come from
+pub const DEFAULT_FILE_URL_TEMPLATE: &str = "{base}{ref}/chatpack/{path}"; // how the url of a file in the pack is built by default: {base} is the raw file url, {ref} the git ref, and {path} the file's path under TARGET_DIR; {hash} (the file's hash) and {object} (it's name in the content-addressed layout, such as ab/cdef...) are also available
+pub const OBJECT_URL_TEMPLATE: &str = "{base}{ref}/objects/{object}"; // how the url of a file is built when the source publishes objects
+pub const CHANNELS: [(&str, &str); 3] = [("stable", "master"), ("beta", "beta"), ("dev", "dev")]; // update channels, and the branch each one's manifest and files come from
+pub const DEFAULT_CHANNEL: &str = "stable";
+pub const SETTINGS_FILENAME: &str = "chatpack-updater.settings"; // the file (in the mush folder) the updater saves it's settings to
+pub const REF_FILENAME: &str = "chatpack.update-ref"; // the file (next to the manifest) holding the commit the published manifest was built from, so every file can be fetched from that exact commit
+pub const VERSIONS_FILENAME: &str = "chatpack.update-versions"; // the file (next to the manifest) mapping every published version to the commit it can be installed from
+pub const INSTALLED_MANIFEST_FILENAME: &str = "chatpack-installed.update-manifest"; // the copy of the manifest the updater last installed, kept in the mush folder to tell user changes apart from outdated files
+pub const INSTALLED_COMPONENTS_FILENAME: &str = "chatpack-installed.update-components"; // the component list as of the updater's last update, kept in the mush folder so disabled components stay left out if the latest list can't be retrieved
+pub const POLICY_RULES_FILENAME: &str = "chatpack.update-policy"; // the file (next to the manifest) with ignore-style patterns giving files an update policy; only read when building the manifest
+pub const POLICY_INDEX_FILENAME: &str = "chatpack.update-policies"; // the file (next to the manifest) mapping paths to their update policy, built from the policy file
+pub const MERGE_BASE_DIRNAME: &str = "chatpack-merge-base"; // the directory in the mush folder holding the last upstream copy of each file with the merge policy
+pub const COMPONENT_RULES_FILENAME: &str = "chatpack.update-component-rules"; // the file (next to the manifest) with ignore-style patterns grouping files into optional components; only read when building the manifest
+pub const COMPONENTS_FILENAME: &str = "chatpack.update-components"; // the file (next to the manifest) listing the optional components, with their descriptions, sizes and files
 pub const STANDARD_UPDATER_IGNORE_FILENAME: &str = "chatpack-standard.update-ignore"; // the name of the file with git ignore syntax specifying files and directories all components of the updater will ignore by default
 pub const CUSTOM_UPDATER_IGNORE_FILENAME: &str = "chatpack-custom.update-ignore"; // the name of the file with git ignore syntax specifying files and directories all components of the updater will ignore; this is meant for use by the user, and gets applied after the standard patterns
-pub const BASE_FILE_URL: &str = "https://git.chatmud.com/athlon/chatpack/raw/master/chatpack/"; // base url which individual filenames will get added to
+pub const PROTECT_FILENAME: &str = "chatpack-custom.update-protect"; // the name of the file (in ignore file syntax) listing files the user wants tracked, but never overwritten without their say so
+pub const CHANGELOG_FILENAME: &str = "chatpack.changelog"; // the file (next to the manifest) holding changelog entries keyed by version
+pub const LOCAL_CHANGES_FILENAME: &str = "chatpack-changes.txt"; // where the updater writes the changes it just installed, so the user can read them later
+pub const PATCHES_DIRNAME: &str = "patches"; // directory (next to the manifest) holding binary patches, at patches/<old hash>/<new hash>
+pub const PATCH_INDEX_FILENAME: &str = "chatpack.update-patches"; // the file (next to the manifest) listing which patches exist
+pub const PATCH_MIN_SIZE: u64 = 64 * 1024; // files smaller than this (in bytes) aren't worth creating patches for
+pub const GZIP_SUFFIX: &str = ".gz"; // added to the manifest's url / a file's path to get it's pre-compressed copy
+pub const COMPRESSED_DIRNAME: &str = "compressed"; // directory (next to the manifest) holding gzipped copies of text files, under the same relative paths
+pub const COMPRESSED_INDEX_FILENAME: &str = "chatpack.update-compressed"; // the file (next to the manifest) listing which files have compressed copies
+pub const OBJECTS_DIRNAME: &str = "objects"; // the directory (next to the manifest) holding every published file under it's hash
+pub const OBJECT_INDEX_FILENAME: &str = "chatpack.update-objects"; // the file (next to the manifest) listing the hash of every object in OBJECTS_DIRNAME
+pub const CACHE_DIRNAME: &str = "chatpack-updater-cache"; // the directory (under the user's cache folder) the shared content cache is kept in
+pub const DEFAULT_CACHE_LIMIT_MB: u64 = 1024; // how big the shared content cache can get, in megabytes, unless the settings say otherwise
+pub const BUNDLE_INFO_FILENAME: &str = "chatpack.update-bundle"; // the file in an offline update bundle saying which version it updates from and to
+pub const BUNDLE_SUFFIX: &str = ".update-bundle.tar.gz"; // added to the name of an offline update bundle
+pub const BUNDLE_CHECKSUM_SUFFIX: &str = ".checksum"; // added to a bundle's file name to get the file holding it's checksum
+pub const BUNDLED_EXTRAS: [&str; 3] = [POLICY_INDEX_FILENAME, COMPONENTS_FILENAME, CHANGELOG_FILENAME]; // files published next to the manifest that go into a bundle with it, when they exist
+pub const DEFAULT_SERVE_ADDRESS: &str = "0.0.0.0:8000"; // where `update-manifest serve` listens unless it's told otherwise
+pub const GIT_MIRRORS_DIRNAME: &str = "chatpack-updater-git"; // the directory (next to the content cache) holding copies of remote git repositories updates are read from
+pub const MUSH_FOLDER_MARKERS: [&str; 3] = ["MUSHclient.exe", "worlds", "mushclient_prefs.sqlite"]; // files and directories that indicate a mush client directory
+pub const MUSH_FOLDER_SEARCH_DEPTH: usize = 4; // how far below a folder `update-many --discover` looks for mush folders
+pub const COMPRESSIBLE_EXTENSIONS: [&str; 9] = ["lua", "xml", "txt", "mcl", "ini", "json", "html", "js", "py"]; // file extensions worth publishing compressed copies of
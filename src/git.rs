@@ -0,0 +1,136 @@
+// reading updates straight out of chatpack's git repository with the git command line tools: a local clone, a bare repository, or a remote one over http
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use serde::de::DeserializeOwned;
+use crate::constants::*;
+use crate::cache;
+use crate::source::is_commit_id;
+use crate::version::Version;
+use crate::versions::VersionIndex;
+
+pub struct GitRepo {
+    dir: PathBuf,
+    remote: bool, // if set, `dir` is a mirror of a remote repository, and refs are fetched into it as they're needed
+}
+
+/// Where the mirror of the remote repository at `url` is kept: next to the content cache, in the user's cache folder
+pub fn mirror_dir (url: &str) -> Option<PathBuf> {
+    let name: String = url.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    Some(cache::default_dir()?.with_file_name(GIT_MIRRORS_DIRNAME).join(name))
+}
+
+impl GitRepo {
+    /// The repository (or clone) at `dir`
+    pub fn local(dir: &Path) -> GitRepo {
+        GitRepo {dir: dir.to_path_buf(), remote: false}
+    }
+
+    /// Open the repository at `location`, which is either a local path or a url (usually http, but anything git understands works); a remote repository gets a mirror in the user's cache folder, holding only the files it's asked for
+    pub fn open(location: &str) -> Result<GitRepo, String> {
+        if !location.contains("://") {
+            return Ok(GitRepo::local(Path::new(location)));
+        }
+        let dir = mirror_dir(location).ok_or_else(|| "Can't tell where to keep a copy of the git repository".to_string())?;
+        let repo = GitRepo {dir: dir.clone(), remote: true};
+        if !dir.exists() {
+            fs::create_dir_all(&dir).map_err(|why| format!("Can't create {}: {}", dir.display(), why))?;
+            let set_up = repo.git(&["init", "-q", "--bare"])
+                .and_then(|_| repo.git(&["remote", "add", "origin", location]))
+                // file contents are only downloaded when they're read
+                .and_then(|_| repo.git(&["config", "core.repositoryformatversion", "1"]))
+                .and_then(|_| repo.git(&["config", "extensions.partialclone", "origin"]))
+                .and_then(|_| repo.git(&["config", "remote.origin.promisor", "true"]))
+                .and_then(|_| repo.git(&["config", "remote.origin.partialclonefilter", "blob:none"]));
+            if let Err(why) = set_up {
+                let _ = fs::remove_dir_all(&dir);
+                return Err(why);
+            }
+        }
+        Ok(repo)
+    }
+
+    /// Run git on this repository, returning what it printed
+    fn git(&self, args: &[&str]) -> Result<Vec<u8>, String> {
+        let out = Command::new("git")
+            .arg("-C").arg(&self.dir)
+            .args(args)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|why| format!("Can't run git: {}", why))?;
+        if !out.status.success() {
+            return Err(format!("git {} failed: {}", args[0], String::from_utf8_lossy(&out.stderr).trim()));
+        }
+        Ok(out.stdout)
+    }
+
+    /// The commit `git_ref` (a branch, tag or commit) points to, fetching it first if the repository is remote
+    pub fn resolve(&self, git_ref: &str) -> Result<String, String> {
+        // a commit that's already here never changes, so it doesn't need fetching again
+        let rev = if self.remote && !(is_commit_id(git_ref) && self.git(&["cat-file", "-e", &format!("{}^{{commit}}", git_ref)]).is_ok()) {
+            let local_ref = format!("refs/chatpack/{}", git_ref);
+            self.git(&["fetch", "-q", "--filter=blob:none", "origin", &format!("+{}:{}", git_ref, local_ref)])?;
+            local_ref
+        } else {
+            git_ref.to_string()
+        };
+        let out = self.git(&["rev-parse", "--verify", "-q", &format!("{}^{{commit}}", rev)])
+            .map_err(|_| format!("The git repository doesn't have {}", git_ref))?;
+        Ok(String::from_utf8_lossy(&out).trim().to_string())
+    }
+
+    /// The contents of `path` (relative to the top of the repository) at `commit`
+    pub fn read(&self, commit: &str, path: &str) -> Result<Vec<u8>, String> {
+        self.git(&["cat-file", "blob", &format!("{}:{}", commit, path)])
+    }
+
+    /// Returns true if `path` exists at `commit`
+    pub fn has(&self, commit: &str, path: &str) -> bool {
+        self.git(&["cat-file", "-e", &format!("{}:{}", commit, path)]).is_ok()
+    }
+
+    /// Read and parse a json file at `commit`
+    pub fn read_json<T: DeserializeOwned>(&self, commit: &str, path: &str) -> Result<T, String> {
+        let bytes = self.read(commit, path)?;
+        serde_json::from_slice(&bytes).map_err(|why| format!("Error parsing {} at {}: {}", path, commit, why))
+    }
+
+    /// Write the contents of `path` at `commit` to `dest`, without holding it all in memory
+    pub fn write_to(&self, commit: &str, path: &str, dest: &Path) -> Result<(), String> {
+        let f = File::create(dest).map_err(|why| format!("couldn't create or open {}: {}", dest.display(), why))?;
+        let status = Command::new("git")
+            .arg("-C").arg(&self.dir)
+            .args(&["cat-file", "blob", &format!("{}:{}", commit, path)])
+            .stdin(Stdio::null())
+            .stdout(f)
+            .stderr(Stdio::null())
+            .status()
+            .map_err(|why| format!("Can't run git: {}", why))?;
+        if !status.success() {
+            let _ = fs::remove_file(dest);
+            return Err(format!("the git repository doesn't have {} at {}", path, commit));
+        }
+        Ok(())
+    }
+
+    /// chatpack's version as of `commit`
+    pub fn version_at(&self, commit: &str) -> Option<Version> {
+        let bytes = self.read(commit, &format!("{}/{}", TARGET_DIR, VERSION_FILENAME)).ok()?;
+        Version::parse(&String::from_utf8_lossy(&bytes)).ok()
+    }
+
+    /// Build a version index from the history of `commit`: every commit that changed the manifest, mapped from the version it published; where several have the same version, the newest wins
+    pub fn version_index(&self, commit: &str) -> Result<VersionIndex, String> {
+        let log = self.git(&["log", "--format=%H", commit, "--", MANIFEST_FILENAME])?;
+        let mut index = BTreeMap::new();
+        // git log lists the newest commits first
+        for c in String::from_utf8_lossy(&log).lines() {
+            if let Some(v) = self.version_at(c) {
+                index.entry(v.to_string()).or_insert_with(|| c.to_string());
+            }
+        }
+        Ok(index)
+    }
+}
@@ -0,0 +1,95 @@
+// keeping track of what the updater last installed, so files the user changed themselves can be told apart from ones that are just out of date
+
+use std::path::{Path, PathBuf};
+use crate::constants::{INSTALLED_MANIFEST_FILENAME, INSTALLED_COMPONENTS_FILENAME};
+use crate::components::Components;
+use crate::manifest::{self, Hashes, ManifestDiff};
+use crate::utils;
+
+/// What to do with a file the user has changed, when upstream has changed it too
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModifiedAction {
+    Backup, // move the user's copy aside, then install the new one
+    Keep, // leave the user's copy alone
+    SaveNew, // leave the user's copy alone, and save the new version next to it
+}
+
+impl ModifiedAction {
+    pub fn from_string(action: &str) -> Option<ModifiedAction> {
+        match action.trim().to_lowercase().as_str() {
+            "backup" | "b" => Some(ModifiedAction::Backup),
+            "keep" | "k" => Some(ModifiedAction::Keep),
+            "save-new" | "s" => Some(ModifiedAction::SaveNew),
+            _ => None,
+        }
+    }
+
+    /// Describes what happened to a file this action was applied to, for the report at the end of an update
+    pub fn describe(&self) -> &'static str {
+        match *self {
+            ModifiedAction::Backup => "backed up and replaced",
+            ModifiedAction::Keep => "kept your copy",
+            ModifiedAction::SaveNew => "kept your copy, saved the new one next to it",
+        }
+    }
+}
+
+/// Load the manifest the updater last installed under `cp_path`, if there is one
+pub fn load (cp_path: &Path) -> Option<Hashes> {
+    let p = cp_path.join(INSTALLED_MANIFEST_FILENAME);
+    if !p.exists() {
+        return None;
+    }
+    manifest::load_file(&p).ok()
+}
+
+/// Record `installed` as the manifest the updater last installed under `cp_path`
+pub fn save (cp_path: &Path, installed: &Hashes) -> Result<(), String> {
+    utils::save_json(&cp_path.join(INSTALLED_MANIFEST_FILENAME), installed, "installed manifest")
+}
+
+/// Load the component list as of the updater's last update under `cp_path`, if it saved one
+pub fn load_components (cp_path: &Path) -> Option<Components> {
+    let p = cp_path.join(INSTALLED_COMPONENTS_FILENAME);
+    if !p.exists() {
+        return None;
+    }
+    utils::load_json_file(&p).ok()
+}
+
+/// Record `components` as the component list of the update just done under `cp_path`, so the next update can go by it if it can't get the latest one
+pub fn save_components (cp_path: &Path, components: &Components) -> Result<(), String> {
+    utils::save_json(&cp_path.join(INSTALLED_COMPONENTS_FILENAME), components, "installed component list")
+}
+
+/// Of the files `diff` says need updating, return the ones the user has changed
+///
+/// A file is user-modified if it's local hash matches neither the hash the updater last installed nor the new upstream one; without a record of what was last installed, nothing can be called user-modified
+pub fn user_modified (diff: &ManifestDiff, local: &Hashes, installed: Option<&Hashes>) -> Vec<String> {
+    let installed = match installed {
+        Some(i) => i,
+        None => return vec![],
+    };
+    diff.modified.iter()
+        .filter(|f| installed.get(*f) != local.get(*f))
+        .cloned()
+        .collect()
+}
+
+/// Where a user's copy of `p` gets moved to before it's replaced; an existing backup is never overwritten
+pub fn backup_path (p: &Path) -> PathBuf {
+    let name = p.file_name().unwrap().to_string_lossy().to_string();
+    let mut candidate = p.with_file_name(format!("{}.backup", name));
+    let mut n = 1;
+    while candidate.exists() {
+        candidate = p.with_file_name(format!("{}.backup.{}", name, n));
+        n += 1;
+    }
+    candidate
+}
+
+/// Where the new version of `p` gets saved when the user's copy is being kept
+pub fn new_copy_path (p: &Path) -> PathBuf {
+    let name = p.file_name().unwrap().to_string_lossy().to_string();
+    p.with_file_name(format!("{}.new", name))
+}
@@ -6,9 +6,36 @@
 pub mod version;
 pub mod constants;
 pub mod utils;
+pub mod manifest;
+pub mod changelog;
+pub mod patch;
+pub mod compression;
+pub mod source;
+pub mod settings;
+pub mod versions;
+pub mod installed;
+pub mod policy;
+pub mod components;
+pub mod paths;
+pub mod objects;
+pub mod cache;
+pub mod bundle;
+pub mod serve;
+pub mod git;
 
 extern crate chrono;
 extern crate checksums;
 extern crate gitignore;
 extern crate walkdir;
 extern crate url;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate reqwest;
+extern crate flate2;
+extern crate bsdiff;
+extern crate diffy;
+extern crate unicode_normalization;
+extern crate tar;
+extern crate blake2_rfc;
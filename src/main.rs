@@ -2,150 +2,625 @@
 
 // This program Hashes files under `TARGET_DIR`, then compares that to a downloaded manifest it retrieves from the repository, then replaces files who's hashes differ
 
-use std::io::{stdout, stderr};
-use std::fs::{OpenOptions, read_dir, create_dir_all, rename};
-use std::path::PathBuf;
+use std::io::{self, stdout, stderr};
+use std::io::prelude::*;
+use std::fs::{self, File, OpenOptions, create_dir_all, rename};
+use std::path::{Path, PathBuf};
 use std::env;
 use std::collections::{BTreeSet, BTreeMap};
-use std::error::Error;
 // pull in checksums
 extern crate checksums;
-use checksums::ops::{create_hashes, compare_hashes, CompareResult, CompareFileResult};
+use checksums::ops::create_hashes;
 use indicatif::{ProgressBar, ProgressStyle};
+use walkdir::WalkDir;
 
 extern crate chatpack_updater; // pull in our library crate so every binary can use things it reexports
 use chatpack_updater::utils;
+use chatpack_updater::manifest;
+use chatpack_updater::changelog;
+use chatpack_updater::patch;
+use chatpack_updater::compression;
+use chatpack_updater::source::{self, Source};
+use chatpack_updater::settings::Settings;
+use chatpack_updater::versions;
+use chatpack_updater::installed::{self, ModifiedAction};
+use chatpack_updater::policy::{self, Policy};
+use chatpack_updater::components;
+use chatpack_updater::paths;
+use chatpack_updater::objects;
+use chatpack_updater::cache::Cache;
+use chatpack_updater::bundle::{self, BundleInfo};
+use chatpack_updater::git::GitRepo;
+use serde::de::DeserializeOwned;
+use chatpack_updater::version::Version;
 
 extern crate reqwest;
 
-extern crate gitignore;
-
 // get constants
 use chatpack_updater::constants::*;
 
 fn main () {
     let cp_path: PathBuf = env::current_dir().unwrap();
-    let mush_directory_markers: Vec<&str> = ["MUSHclient.exe", "worlds", "mushclient_prefs.sqlite"].to_vec(); // Files and directories that indicate a mush client directory
+    let args: Vec<String> = env::args().collect();
+    // `chatpack-updater update-many` updates other mush folders, so it can be run from anywhere
+    if args.len() > 1 && args[1] == "update-many" {
+        update_many(&args[2..]);
+        return;
+    }
     // make sure this program is located inside a mush directory
-    // do this by getting a list of `Path` instances for the current directory and looping through the marker paths to see if it contains one of them
-    // do this in a different scope so we don't keep around variables that aren't needed afterwards
-    {
-        let entries: Vec<PathBuf> = read_dir(&cp_path).expect("Can't read files in current directory.")
-          .map(| e | {
-            match e {
-                Ok(e) => e.path(),
-                Err(why) => panic!("Can't read entry in current directory: {}", why.description()),
-            } // end the match
-          }) // end the closure
-          .collect();
-        let mut marker_found= false;
-        let dir: PathBuf = env::current_dir().unwrap();
-        for e in &mush_directory_markers {
-            let mut e_path: PathBuf = dir.clone();
-            e_path.push(&e);
-            if entries.contains(&e_path) {
-                marker_found = true
-            }
+    if !is_mush_folder(&cp_path) {
+        println!("You must run the {} updater from your mush folder.", TARGET_DIR);
+        return;
+    }
+    let mut settings = Settings::load(&cp_path);
+    // `chatpack-updater explain-ignore <path>` says whether a file is ignored and why; `list-ignored` lists everything that is
+    if args.len() > 1 && args[1] == "explain-ignore" {
+        match args.get(2) {
+            Some(path) => println!("{}", utils::explain_ignore(&cp_path, path)),
+            None => println!("Usage: chatpack-updater explain-ignore <path>"),
         }
-        if !marker_found {
-            println!("You must run the {} updater from your mush folder.", TARGET_DIR);
+        return;
+    }
+    if args.len() > 1 && args[1] == "list-ignored" {
+        for path in utils::list_ignored(&cp_path) {
+            println!("{}", path);
+        }
+        return;
+    }
+    // `chatpack-updater channel [name]` shows or changes which channel updates come from
+    if args.len() > 1 && args[1] == "channel" {
+        channel_command(&cp_path, &mut settings, args.get(2));
+        return;
+    }
+    // `chatpack-updater cache [on|off|limit <megabytes>|prune]` manages the content cache shared with other mush folders
+    if args.len() > 1 && args[1] == "cache" {
+        cache_command(&cp_path, &mut settings, &args[2..]);
+        return;
+    }
+    // `--channel <name>` updates from a channel just this once, without changing the saved setting
+    let channel = utils::arg_value(&args, "--channel")
+        .or_else(|| settings.channel.clone())
+        .unwrap_or_else(|| DEFAULT_CHANNEL.to_string());
+    // `--source <url>` gets updates from somewhere else (such as a computer running `update-manifest serve`) just this once
+    let source_url = utils::arg_value(&args, "--source").or_else(|| settings.source_url.clone());
+    // `--git <repository>` reads updates straight out of a git repository (a local clone, or a url) just this once
+    let git_repo = utils::arg_value(&args, "--git").or_else(|| settings.git_repo.clone());
+    let source = match channel_source(&channel, source_url, git_repo, &settings) {
+        Ok(s) => s,
+        Err(why) => {
+            println!("{}", why);
             return;
+        },
+    };
+    // `--modified backup|keep|save-new` says what to do with files the user has changed that upstream has changed too; without it, the updater asks
+    let modified_action = match utils::arg_value(&args, "--modified") {
+        Some(a) => match ModifiedAction::from_string(&a) {
+            Some(a) => Some(a),
+            None => {
+                println!("--modified should be one of backup, keep, or save-new.");
+                return;
+            },
+        },
+        None => None,
+    };
+    match args.get(1).map(|a| a.as_str()) {
+        // `chatpack-updater status` shows what an update would change, without changing anything
+        Some("status") => {
+            status(&cp_path, source);
+            return;
+        },
+        // `chatpack-updater verify` checks files against what the updater last installed, without going online
+        Some("verify") => {
+            verify(&cp_path);
+            return;
+        },
+        // `chatpack-updater components` lists the optional components; `enable <name>` and `disable <name>` choose which get installed
+        Some(command @ "components") | Some(command @ "enable") | Some(command @ "disable") => {
+            components_command(&cp_path, &mut settings, &source, command, args.get(2));
+            return;
+        },
+        // `chatpack-updater apply-bundle <file>` updates from an offline bundle, without going online
+        Some("apply-bundle") => {
+            match args.get(2) {
+                Some(b) => apply_bundle(cp_path, Path::new(b), modified_action),
+                None => println!("Usage: chatpack-updater apply-bundle <bundle file>"),
+            }
+            return;
+        },
+        // `chatpack-updater rollback` goes back to the version before the installed one
+        Some("rollback") => {
+            let installed = match read_version(&cp_path) {
+                Some(v) => v,
+                None => {
+                    println!("Can't tell which version of {} is installed, so there's nothing to roll back from.", TARGET_DIR);
+                    return;
+                },
+            };
+            let index = match load_versions(&source) {
+                Ok(i) => i,
+                Err(why) => {
+                    println!("Can't retrieve the list of published versions: {}", why);
+                    return;
+                },
+            };
+            match versions::previous(&index, &installed) {
+                Some((v, commit)) => {
+                    println!("Rolling back from version {} to {}.", installed.to_string(), v.to_string());
+                    update(cp_path, source.at_ref(&commit), modified_action);
+                    println!("Run `chatpack-updater hold` if you'd like to stay on this version.");
+                },
+                None => println!("There's no published version older than {}.", installed.to_string()),
+            }
+            return;
+        },
+        // `chatpack-updater install --version <v>` installs a specific version
+        Some("install") => {
+            match utils::arg_value(&args, "--version") {
+                Some(v) => install_version(cp_path, &source, &v, modified_action),
+                None => println!("Usage: chatpack-updater install --version <version>"),
+            }
+            return;
+        },
+        // `chatpack-updater hold [version]` stops updates from moving past a version (the installed one, by default)
+        Some("hold") => {
+            let held = match args.get(2) {
+                Some(v) => Version::parse(v).map(|v| v.to_string()),
+                None => read_version(&cp_path).map(|v| v.to_string()).ok_or_else(|| format!("Can't tell which version of {} is installed; give the version to hold at.", TARGET_DIR)),
+            };
+            match held {
+                Ok(v) => {
+                    settings.hold = Some(v.clone());
+                    match settings.save(&cp_path) {
+                        Ok(_) => println!("Updates are now held at version {}.", v),
+                        Err(why) => println!("Unable to save the hold: {}", why),
+                    }
+                },
+                Err(why) => println!("{}", why),
+            }
+            return;
+        },
+        Some("unhold") => {
+            settings.hold = None;
+            match settings.save(&cp_path) {
+                Ok(_) => println!("Updates are no longer held; the next update will get the latest version."),
+                Err(why) => println!("Unable to save settings: {}", why),
+            }
+            return;
+        },
+        _ => (),
+    }
+    if channel != DEFAULT_CHANNEL {
+        println!("Updating from the {} channel.", channel);
+    }
+    // with a hold in place, updating means making sure the held version is what's installed
+    if let Some(ref held) = settings.hold {
+        println!("Updates are held at version {}; run `chatpack-updater unhold` to get the latest.", held);
+        install_version(cp_path, &source, held, modified_action);
+        return;
+    }
+    update(cp_path, source, modified_action);
+}
+
+/// The source updates on `channel` come from, at `source_url` or in `git_repo` if either is given, built the way `settings` say
+fn channel_source (channel: &str, source_url: Option<String>, git_repo: Option<String>, settings: &Settings) -> Result<Source, String> {
+    let source = match source::for_channel(channel) {
+        Some(s) => s,
+        None => return Err(format!("There's no '{}' update channel; the channels are {}.", channel, channel_names())),
+    };
+    let source = match source_url {
+        Some(url) => source.at_base(&url),
+        None => source,
+    };
+    let source = match git_repo {
+        Some(location) => source.in_git_repo(&location),
+        None => source,
+    };
+    // a mirror might lay files out differently, so the url template can be changed in the settings
+    match settings.url_template {
+        Some(ref template) => source.with_template(template).map_err(|why| format!("Can't use the url template in {}: {}", SETTINGS_FILENAME, why)),
+        None => Ok(source),
+    }
+}
+
+/// The source the version of chatpack given by `version_string` can be installed from: `source`, at the commit the version index says it was published at
+fn version_source (source: &Source, version_string: &str) -> Result<Source, String> {
+    let version = Version::parse(version_string)?;
+    let index = load_versions(source).map_err(|why| format!("Can't retrieve the list of published versions: {}", why))?;
+    match versions::commit_for(&index, &version) {
+        Some(commit) => Ok(source.at_ref(&commit)),
+        None => Err(format!("Version {} hasn't been published.", version.to_string())),
+    }
+}
+
+/// Install the version of chatpack given by `version_string`, by updating against the commit the version index says it was published at
+fn install_version (cp_path: PathBuf, source: &Source, version_string: &str, modified_action: Option<ModifiedAction>) {
+    match version_source(source, version_string) {
+        Ok(s) => update(cp_path, s, modified_action),
+        Err(why) => println!("{}", why),
+    }
+}
+
+/// Update from an offline bundle made by `update-manifest bundle`, checking it's files against the manifest in it just as downloads are checked
+fn apply_bundle (cp_path: PathBuf, bundle_path: &Path, modified_action: Option<ModifiedAction>) {
+    match bundle::verify_checksum(bundle_path) {
+        Ok(true) => (),
+        // the bundle lists the hash of everything in it, so it's still checked as a whole as it's unpacked
+        Ok(false) => (),
+        Err(why) => {
+            println!("{}", why);
+            return;
+        },
+    }
+    let dir = env::temp_dir().join(format!("chatpack-bundle-{}", std::process::id()));
+    match bundle::unpack(bundle_path, &dir) {
+        Ok(info) => {
+            // the bundle only holds what changed since the version it was made from, so it can't update any other
+            let installed = read_version(&cp_path).map(|v| v.to_string());
+            if installed.as_ref() == Some(&info.to) {
+                println!("Version {} is already installed.", info.to);
+            } else if installed.as_ref() != Some(&info.from) {
+                println!("This bundle updates version {} to {}, but {} is installed; you'll need a bundle made from the version you have.", info.from, info.to, installed.map_or_else(|| "an unknown version".to_string(), |v| format!("version {}", v)));
+            } else {
+                println!("Updating from version {} to {} with {}.", info.from, info.to, bundle_path.display());
+                match Upstream::from_bundle(&dir, info) {
+                    Ok(mut upstream) => {
+                        update_install(&cp_path, &mut upstream, modified_action);
+                    },
+                    Err(why) => println!("Can't use the bundle: {}", why),
+                }
+            }
+        },
+        Err(why) => println!("{}", why),
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// Returns true if `dir` looks like a mush client folder
+fn is_mush_folder (dir: &Path) -> bool {
+    MUSH_FOLDER_MARKERS.iter().any(|m| dir.join(m).exists())
+}
+
+/// Find the mush folders under `dir` (including `dir` itself); folders inside a mush folder aren't searched
+fn find_mush_folders (dir: &Path) -> Vec<PathBuf> {
+    let mut found = vec![];
+    let mut walker = WalkDir::new(dir).max_depth(MUSH_FOLDER_SEARCH_DEPTH).into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(_) => continue, // folders we can't read can't be updated anyway
+        };
+        if entry.file_type().is_dir() && is_mush_folder(entry.path()) {
+            found.push(entry.path().to_path_buf());
+            walker.skip_current_dir();
         }
     }
-    // identify the path to this program
-    let this_prog_path = env::current_exe().expect("Unable to get the path to the updater.");
-    let this_prog_name = this_prog_path.file_name().unwrap();
-    // get a reqwest client instance (for http requests)
-    let r_client = reqwest::Client::new();
-    println!("Retrieving a snapshot of what files in the latest version look like...");
-    // now, before doing any work hashing files, try to download the hash manifest from the repository that we'll need to compare against
-    // do this in a different scope so we don't have variables sticking around that won't be used later
-    // keep the `master_manifest` variable, though
-    let master_manifest;
-    {
-        let mut resp = match r_client.get(MASTER_MANIFEST_URL).send() {
-            Ok(r) => r,
-            Err(why) => panic!("Can't retrieve the manifest file needed to update: {}", why.description()),
+    found
+}
+
+/// Update several mush folders in one run: the ones named in `args`, and any found under a folder given with `--discover`
+///
+/// Installs that get updates from the same place share one copy of the manifest, and files one of them downloads are copied to the rest rather than downloaded again
+fn update_many (args: &[String]) {
+    let mut folders: Vec<PathBuf> = vec![];
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--discover" => {
+                match args.get(i + 1) {
+                    Some(dir) => {
+                        let found = find_mush_folders(Path::new(dir));
+                        if found.is_empty() {
+                            println!("Warning: no mush folders were found under {}.", dir);
+                        }
+                        folders.extend(found);
+                    },
+                    None => {
+                        println!("--discover needs a folder to search.");
+                        return;
+                    },
+                }
+                i += 1;
+            },
+            "--channel" | "--modified" | "--source" | "--git" => i += 1, // read below
+            a if a.starts_with("--") => (),
+            folder => folders.push(PathBuf::from(folder)),
+        }
+        i += 1;
+    }
+    if folders.is_empty() {
+        println!("Usage: chatpack-updater update-many [--discover <folder>] [<mush folder>...]");
+        return;
+    }
+    let modified_action = match utils::arg_value(args, "--modified") {
+        Some(a) => match ModifiedAction::from_string(&a) {
+            Some(a) => Some(a),
+            None => {
+                println!("--modified should be one of backup, keep, or save-new.");
+                return;
+            },
+        },
+        None => None,
+    };
+    let channel_override = utils::arg_value(args, "--channel");
+    let source_override = utils::arg_value(args, "--source");
+    let git_override = utils::arg_value(args, "--git");
+    // work out where each install gets it's updates from, and group the ones that get them from the same place
+    let mut report: Vec<(PathBuf, Option<Version>, Result<(), String>)> = vec![];
+    let mut groups: Vec<(Source, Vec<PathBuf>)> = vec![];
+    let mut seen = BTreeSet::new();
+    for folder in folders {
+        let folder = match fs::canonicalize(&folder) {
+            Ok(f) => f,
+            Err(why) => {
+                report.push((folder, None, Err(why.to_string())));
+                continue;
+            },
         };
-        if !resp.status().is_success() {
-            println!("Can't retrieve the manifest file needed to update: ChatMUD's git returned status code {}. Please try again later.", resp.status());
+        if !seen.insert(folder.clone()) {
+            continue;
+        }
+        let before = read_version(&folder);
+        if !is_mush_folder(&folder) {
+            report.push((folder, before, Err("it isn't a mush folder".to_string())));
+            continue;
+        }
+        let settings = Settings::load(&folder);
+        let channel = channel_override.clone()
+            .or_else(|| settings.channel.clone())
+            .unwrap_or_else(|| DEFAULT_CHANNEL.to_string());
+        let source_url = source_override.clone().or_else(|| settings.source_url.clone());
+        let git_repo = git_override.clone().or_else(|| settings.git_repo.clone());
+        let source = channel_source(&channel, source_url, git_repo, &settings)
+            .and_then(|s| match settings.hold {
+                Some(ref held) => version_source(&s, held),
+                None => Ok(s),
+            });
+        match source {
+            Ok(s) => match groups.iter_mut().find(|g| g.0 == s) {
+                Some(group) => group.1.push(folder),
+                None => groups.push((s, vec![folder])),
+            },
+            Err(why) => report.push((folder, before, Err(why))),
+        }
+    }
+    for (source, installs) in groups {
+        let mut upstream = Upstream::fetch(source);
+        for folder in installs {
+            let before = read_version(&folder);
+            let result = match upstream {
+                Ok(ref mut u) => {
+                    println!();
+                    println!("Updating {}:", folder.display());
+                    if update_install(&folder, u, modified_action) { Ok(()) } else { Err("the update didn't finish".to_string()) }
+                },
+                Err(ref why) => Err(format!("couldn't retrieve the manifest: {}", why)),
+            };
+            report.push((folder, before, result));
+        }
+    }
+    println!();
+    println!("Summary:");
+    let describe = |v: Option<Version>| v.map(|v| v.to_string()).unwrap_or_else(|| "unknown".to_string());
+    for (folder, before, result) in report {
+        match result {
+            Ok(_) => println!("  {}: {} -> {}", folder.display(), describe(before), describe(read_version(&folder))),
+            Err(why) => println!("  {}: not updated ({}), still at {}", folder.display(), why, describe(before)),
+        }
+    }
+}
+
+/// Bring the chatpack install at `cp_path` in line with the manifest `source` publishes
+///
+/// Switching channels is just an update against a different source; the comparison works the same way whether that means moving forward or back
+fn update (cp_path: PathBuf, source: Source, modified_action: Option<ModifiedAction>) {
+    let mut upstream = match Upstream::fetch(source) {
+        Ok(u) => u,
+        Err(why) => {
+            println!("Can't retrieve the manifest file needed to update: {}. Please try again later.", why);
             return;
+        },
+    };
+    update_install(&cp_path, &mut upstream, modified_action);
+}
+
+/// What an update gets from upstream before looking at any install, so it only has to be fetched once however many installs are being updated
+struct Upstream {
+    client: reqwest::Client, // asks for compressed responses, so bodies need to be read with `compression::decoded_body`
+    source: Source, // pinned to the commit the manifest was built from, if the repository says which that is
+    manifest: BTreeMap<String, String>,
+    extras: Extras,
+    policies: policy::PolicyIndex,
+    components: Result<components::Components, String>, // an error if the list couldn't be retrieved, as opposed to not being published
+    changelog: changelog::Changelog,
+    fetched: BTreeMap<String, PathBuf>, // files downloaded so far (or unpacked from a bundle), by hash, so they can be copied instead of downloaded again
+    bundle: Option<BundleInfo>, // set when updating from an offline bundle, in which case nothing is downloaded
+    git: Option<GitRepo>, // set when files are read straight out of a git repository, at the commit `source` is at
+}
+
+impl Upstream {
+    /// Pin `source` and get it's manifest and extras
+    fn fetch(source: Source) -> Result<Upstream, String> {
+        if let Some(location) = source.git_repo.clone() {
+            return Upstream::from_git(source, &location);
         }
-        // now that we have a response, get the body and parse
-        let j: BTreeMap<String, String> = resp.json().expect("Error parsing downloaded manifest file.");
-        master_manifest = j;
+        let client = compression::http_client();
+        // a version's changelog entry is committed just after the version itself, so it's read from the branch rather than the pinned commit
+        let changelog = changelog::load(&source.url(CHANGELOG_FILENAME)).unwrap_or_default();
+        // if the repository says which commit it's manifest was built from, get everything else from that commit
+        let source = source.pinned(&client);
+        println!("Retrieving a snapshot of what files in the latest version look like...");
+        let manifest = manifest::fetch(&client, &source.manifest_url())?;
+        // see if there are patches, compressed copies or objects available; not having any just means everything gets downloaded in full by path
+        let extras = Extras::load(&source);
+        // none of these have to be published, so not being able to get them isn't an error
+        let policies = policy::load_index(&source.url(POLICY_INDEX_FILENAME)).unwrap_or_default();
+        let components = components::load(&source.url(COMPONENTS_FILENAME));
+        println!("Done.");
+        Ok(Upstream {client, source, manifest, extras, policies, components, changelog, fetched: BTreeMap::new(), bundle: None, git: None})
     }
-    println!("Done.");
+
+    /// Everything an update needs, read straight out of the git repository at `location`
+    fn from_git(source: Source, location: &str) -> Result<Upstream, String> {
+        println!("Reading what files in the latest version look like from {}...", location);
+        let repo = GitRepo::open(location)?;
+        let mut commit = repo.resolve(&source.git_ref)?;
+        // a version's changelog entry is committed just after the version itself, so it's read from the branch rather than the pinned commit
+        let changelog = repo.read_json(&commit, CHANGELOG_FILENAME).unwrap_or_default();
+        // if the repository says which commit it's manifest was built from, get everything else from that commit
+        if !source::is_commit_id(&source.git_ref) {
+            if let Ok(pin) = repo.read(&commit, REF_FILENAME) {
+                let pin = String::from_utf8_lossy(&pin).trim().to_string();
+                if source::is_commit_id(&pin) {
+                    match repo.resolve(&pin) {
+                        // a pin to a commit that isn't there (one that was never pushed, say) is no use to anyone
+                        Err(why) => println!("Warning: updates are pinned to commit {}, but it can't be used ({}); updating from {} instead.", pin, why, source.git_ref),
+                        // a pin that wasn't moved when a new manifest was committed would keep everyone on the old version
+                        Ok(pinned) => match (repo.version_at(&commit), repo.version_at(&pinned)) {
+                            (Some(latest), Some(at_pin)) if at_pin < latest => println!("Warning: updates are pinned to version {}, but {} is newer; updating from {} instead.", at_pin.to_string(), latest.to_string(), source.git_ref),
+                            _ => commit = pinned,
+                        },
+                    }
+                }
+            }
+        }
+        let manifest = manifest::canonicalize(repo.read_json(&commit, MANIFEST_FILENAME)?);
+        // none of these have to be published, so not being able to get them isn't an error
+        let policies = repo.read_json(&commit, POLICY_INDEX_FILENAME).unwrap_or_default();
+        let components = if repo.has(&commit, COMPONENTS_FILENAME) { repo.read_json(&commit, COMPONENTS_FILENAME) } else { Ok(components::Components::new()) };
+        println!("Done.");
+        Ok(Upstream {
+            client: compression::http_client(),
+            source: source.at_ref(&commit),
+            manifest,
+            // patches and the like only save downloading whole files over the web
+            extras: Extras::default(),
+            policies,
+            components,
+            changelog,
+            fetched: BTreeMap::new(),
+            bundle: None,
+            git: Some(repo),
+        })
+    }
+
+    /// Everything an update needs, from a bundle unpacked into `dir`
+    fn from_bundle(dir: &Path, info: BundleInfo) -> Result<Upstream, String> {
+        let manifest = manifest::load_file(&dir.join(MANIFEST_FILENAME))?;
+        // the bundle's files are checked against the manifest as they're installed, just like downloads
+        let cp_dir = dir.join(TARGET_DIR);
+        let fetched = manifest.iter()
+            .filter(|&(path, _)| cp_dir.join(path).is_file())
+            .map(|(path, hash)| (hash.clone(), cp_dir.join(path)))
+            .collect();
+        let load_extra = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        Ok(Upstream {
+            client: compression::http_client(),
+            source: Source::default(),
+            manifest,
+            extras: Extras::default(),
+            policies: policy::load_index(&load_extra(POLICY_INDEX_FILENAME)).unwrap_or_default(),
+            components: components::load(&load_extra(COMPONENTS_FILENAME)),
+            changelog: changelog::load(&load_extra(CHANGELOG_FILENAME)).unwrap_or_default(),
+            fetched,
+            bundle: Some(info),
+            git: None,
+        })
+    }
+}
+
+/// Update the install at `cp_path` to match `upstream`, returning false if it couldn't be finished
+fn update_install (cp_path: &Path, upstream: &mut Upstream, modified_action: Option<ModifiedAction>) -> bool {
+    let cp_path = cp_path.to_path_buf();
+    // remember which version is installed now, so the changes since then can be shown once the update is done
+    let old_version: Option<Version> = read_version(&cp_path);
+    // identify the path to this program
+    let this_prog_path = env::current_exe().expect("Unable to get the path to the updater.");
+    // creating a test file, so only once per run
+    let case_insensitive = paths::is_case_insensitive(&cp_path);
+    let master_manifest = safe_manifest(upstream.manifest.clone(), case_insensitive);
+    let (wanted_manifest, unwanted) = match wanted_files(&cp_path, &upstream.components, &master_manifest) {
+        Ok(w) => w,
+        Err(why) => {
+            println!("{}", why);
+            return false;
+        },
+    };
     
-    // find all ignored files and directories, so they can be skipped when hashing to save time
-    let mut ignores: BTreeSet<String> = BTreeSet::new();
-    let mut standard_ignores_path = cp_path.clone();
-    standard_ignores_path.push(STANDARD_UPDATER_IGNORE_FILENAME);
-    let mut custom_ignores_path = cp_path.clone();
-    custom_ignores_path.push(CUSTOM_UPDATER_IGNORE_FILENAME);
-    let mut ignore_files: Vec<gitignore::File> = vec!(); // a vec with gitignore file instances; each will be processed and combined
-    if standard_ignores_path.exists() {
-        ignore_files.push(gitignore::File::new(&standard_ignores_path).unwrap());
-    }
-    if custom_ignores_path.exists() {
-        ignore_files.push(gitignore::File::new(&custom_ignores_path).unwrap())
-    }
-    if !ignore_files.is_empty(){
-        // walk our current directory recursively and add relative paths of ignored files and dirs
-        ignores.append(&mut utils::ignored_files(&cp_path, ignore_files));
+    // the standard ignore file is part of the pack, but it has to be brought up to date before it's used; otherwise the old rules could let files upstream now ignores be overwritten
+    let standard_ignores_path = cp_path.join(STANDARD_UPDATER_IGNORE_FILENAME);
+    if let Some(expected_hash) = master_manifest.get(STANDARD_UPDATER_IGNORE_FILENAME) {
+        if !standard_ignores_path.exists() || &checksums::hash_file(&standard_ignores_path, ALGO) != expected_hash {
+            if let Err(why) = get_file(upstream, &cp_path, STANDARD_UPDATER_IGNORE_FILENAME, &standard_ignores_path, None, expected_hash, &this_prog_path, &ProgressBar::hidden()) {
+                println!("Warning: unable to retrieve the latest {}, so the copy you have will be used: {}", STANDARD_UPDATER_IGNORE_FILENAME, why);
+            }
+        }
     }
-    let max_recursion: Option<usize> = Some(10);
-    println!("Taking a snapshot of how files look now...");
-    // Hash files in `TARGET_DIR` to determine what needs to be updated
-    let hashes: BTreeMap<String, String> = create_hashes(&cp_path,
-        ignores,
-        ALGO,
-        max_recursion,
-        true,
-        JOBS,
-        stdout(),
-        &mut stderr()
-    );
-    println!();
-    println!("Done.");
+    let hashes = local_hashes(&cp_path, &master_manifest, case_insensitive);
     println!("Determining what files need updating...");
     // now compare them against the downloaded manifest
-    let res = compare_hashes("", master_manifest, hashes);
-    let mut new_files: Vec<String>;
-    let mut modified_files: Vec<String>;
-    let mut ignored_files: Vec<String>;
-    let mut removed_files: Vec<String>;
-    match res {
-        Ok((cr, fcr)) => {
-            // initialize the variables that need to survive out of this scope
-            // without the next 4 lines, the compiler yells about using uninitialized variables so
-            new_files = [].to_vec();
-            modified_files = [].to_vec();
-            ignored_files = [].to_vec();
-            removed_files = [].to_vec();
-            for r in &cr {
-                match *r {
-                    CompareResult::FileAdded(ref file) => new_files.push(file.to_owned()),
-                    CompareResult::FileRemoved(ref file) => removed_files.push(file.to_owned()),
-                    CompareResult::FileIgnored(ref file) => ignored_files.push(file.to_owned()),
-                } // end the match
-            } // end the for loop
-            // now check for modified files
-            for r in &fcr {
-                match *r {
-                    CompareFileResult::FileMatches(_) => (), // don't do anything if files are the same
-                    CompareFileResult::FileDiffers {ref file, ref was_hash, ref new_hash} => modified_files.push(file.to_owned()),
-                } // end individual file match
-            } // end the for loop
-        } // end ok
-        Err(_) => panic!("Error comparing hashes: hash lengths of the downloaded manifest and locally-generated one differ."),
-    }
+    let diff = match manifest::compare(hashes.clone(), wanted_manifest.clone()) {
+        Ok(d) => d,
+        Err(why) => {
+            println!("{}", why);
+            return false;
+        },
+    };
+    // files the user changed themselves (rather than ones that are just out of date) get handled the way they choose
+    let installed_manifest = installed::load(&cp_path);
+    let mut user_modified = installed::user_modified(&diff, &hashes, installed_manifest.as_ref());
+    // files can have a policy that says how they're updated; those don't need to ask the user
+    let policy_index = upstream.policies.clone();
+    user_modified.retain(|f| !policy_index.contains_key(f));
+    // files the user has protected are only overwritten if they say so (or pass --overwrite-protected); missing ones are just restored
+    let protected = utils::protected_files(&cp_path, diff.modified.iter());
+    user_modified.retain(|f| !protected.contains(f));
+    let overwrite_protected = !protected.is_empty() && (env::args().any(|a| a == "--overwrite-protected") || ask_overwrite_protected(&protected));
+    let new_files: Vec<String> = diff.added;
+    let modified_files: Vec<String> = diff.modified;
     println!("Done. {} new files, {} modified files.", new_files.len(), modified_files.len());
+    let modified_action = if user_modified.is_empty() {
+        ModifiedAction::Keep // not used
+    } else {
+        modified_action.unwrap_or_else(|| ask_modified_action(&user_modified))
+    };
+    let mut modified_report: Vec<String> = vec![];
     
     // Now download the files that are new or have been modified
     let mut ftd = vec![]; // files to download
     ftd.extend(new_files);
     ftd.extend(modified_files);
+    // the version file goes last, so an update that doesn't finish isn't taken for the new version
+    ftd.sort_by_key(|pathstring| pathstring == VERSION_FILENAME);
+    // index what's here by hash, so files upstream moved or copied can be reused rather than downloaded again
+    let mut local_by_hash: BTreeMap<String, String> = hashes.iter()
+        .filter(|&(_, hash)| !utils::is_placeholder_hash(hash))
+        .map(|(path, hash)| (hash.clone(), path.clone()))
+        .collect();
+    // other mush folders on this computer may have downloaded the same files already
+    let cache = Cache::for_install(&cp_path);
+    // a bundle only holds what changed since the version it was made from, so anything else has to be here already; find out before touching anything, and leave what can't be had as it is
+    let mut not_in_bundle = BTreeSet::new();
+    if upstream.bundle.is_some() {
+        for pathstring in &ftd {
+            let expected_hash = &master_manifest[pathstring];
+            let left_alone = (protected.contains(pathstring) && !overwrite_protected)
+                || (policy_index.get(pathstring) == Some(&Policy::IfMissing) && cp_path.join(pathstring).exists())
+                || (user_modified.contains(pathstring) && modified_action == ModifiedAction::Keep);
+            let available = upstream.fetched.contains_key(expected_hash)
+                || local_by_hash.contains_key(expected_hash)
+                || cache.as_ref().and_then(|c| c.path(expected_hash)).map_or(false, |p| p.exists());
+            if !left_alone && !available {
+                not_in_bundle.insert(pathstring.clone());
+            }
+        }
+        if !not_in_bundle.is_empty() {
+            println!("Warning: {} files this update needs aren't in the bundle, and there's no copy of them here, so they'll be left as they are. Updating online will bring them up to date.", not_in_bundle.len());
+            ftd.retain(|pathstring| !not_in_bundle.contains(pathstring));
+            for pathstring in &not_in_bundle {
+                modified_report.push(format!("{}: not in the bundle, so it was left as it is", pathstring));
+            }
+        }
+    }
     let total = ftd.len();
     // now that all the pathstrings are in one vec, loop over them, percent encode them, download them, then write each to disk
     // Oh, and progress bar too.
@@ -155,48 +630,799 @@ fn main () {
       .template("{pos}/{len} - {msg} Remaining: {eta} {bar:>}")
       .progress_chars("#>-")
     );
+    let mut reused = 0;
+    let mut from_cache = 0;
     for pathstring in ftd {
         download_progbar.set_message(&pathstring);
         download_progbar.inc(1);
-        let e = utils::percent_encode_pathstring(&pathstring);
-        //println!("Downloading {:?}", e);
-        let mut url: &str = &format!("{}{}", BASE_FILE_URL, e);
-        //println!("URL: {:?}", url);
-        let mut resp = match r_client.get(url).send() {
-            Ok(response) => response,
-            Err(why) => panic!("Can't retrieve file '{}': {}", pathstring, why.description()),
-        };
-        // We have a valid response; check it's status code
-        if !resp.status().is_success(){
-            println!("Error retrieving file '{}': ChatMUD's git returned status code {}. Please try updating again later.", pathstring, resp.status());
-            return;
-        }
-        // the program has exited if the status wasn't a success; now write the file out to disk.
-        {
-            let mut p: PathBuf = cp_path.clone();
-            p.push(&pathstring);
-            // since open won't create intervening directories, run create_dir_all on path.parent to create any directories up the file that don't exist
-            create_dir_all(&p.parent().unwrap()).unwrap();
-            // check if the file to be updated is actually this program
-            if this_prog_name == p.file_name().unwrap() {
-                // rename this program, passing a '.old' suffix so the new version can be downloaded.
-                rename(&this_prog_path, &this_prog_path.with_extension("old")).expect("Error renaming the updater.");
-            }
-            let f = OpenOptions::new().create(true).write(true).truncate(true).open(&p);
-            match f {
-                Ok(mut file) => {
-                    //println!("Writing to disk...");
-                    // copy the http response (which is the file) to the opened file
-                    resp.copy_to(&mut file).unwrap();
-                    //println!("Done!");
+        let mut p: PathBuf = cp_path.clone();
+        p.push(&pathstring);
+        let expected_hash = &master_manifest[&pathstring];
+        if protected.contains(&pathstring) && !overwrite_protected {
+            modified_report.push(format!("{}: protected, so it was left alone", pathstring));
+            continue;
+        }
+        // files with an update policy are handled the way the policy says, rather than the usual way
+        match (policy_index.get(&pathstring), p.exists()) {
+            (Some(Policy::IfMissing), true) => continue, // it's the user's now
+            (Some(Policy::NeverOverwrite), true) => {
+                p = installed::new_copy_path(&p);
+                modified_report.push(format!("{}: left alone; the new version was saved as {}", pathstring, p.display()));
+            },
+            (Some(Policy::Merge), _) => {
+                match update_merged(upstream, &cp_path, &pathstring, expected_hash, &this_prog_path, &download_progbar) {
+                    Ok(Some(report)) => modified_report.push(report),
+                    Ok(None) => (),
+                    Err(why) => {
+                        println!("Error retrieving file '{}': {}. Please try updating again later.", pathstring, why);
+                        return false;
+                    },
+                }
+                continue;
+            },
+            _ => (),
+        }
+        if user_modified.contains(&pathstring) {
+            match modified_action {
+                ModifiedAction::Keep => {
+                    modified_report.push(format!("{}: {}", pathstring, modified_action.describe()));
+                    continue;
                 },
-                Err(why) => {
-                    println!("Unable to open file '{}': {}", p.display(), why.description());
-                    return;
+                ModifiedAction::Backup => {
+                    let backup = installed::backup_path(&p);
+                    if let Err(why) = rename(&p, &backup) {
+                        download_progbar.println(format!("Unable to back up '{}', so it was left alone: {}", pathstring, why));
+                        modified_report.push(format!("{}: couldn't be backed up, so your copy was kept", pathstring));
+                        continue;
+                    }
+                    modified_report.push(format!("{}: {} (your copy is at {})", pathstring, modified_action.describe(), backup.display()));
+                },
+                ModifiedAction::SaveNew => {
+                    p = installed::new_copy_path(&p);
+                    modified_report.push(format!("{}: {} ({})", pathstring, modified_action.describe(), p.display()));
+                },
+            }
+        }
+        let at_real_path = p == cp_path.join(&pathstring);
+        if let Some(src) = local_by_hash.get(expected_hash).cloned() {
+            // a file upstream no longer has, that the updater put here itself, has been moved; anything else is copied, so nothing of the user's goes missing
+            let move_it = at_real_path && !master_manifest.contains_key(&src) && installed_manifest.as_ref().and_then(|i| i.get(&src)) == Some(expected_hash);
+            match reuse_local(&cp_path.join(&src), &p, expected_hash, move_it, &this_prog_path) {
+                Ok(_) => {
+                    reused += 1;
+                    if at_real_path {
+                        local_by_hash.insert(expected_hash.clone(), pathstring.clone());
+                    }
+                    continue;
                 },
+                Err(why) => download_progbar.println(format!("Unable to reuse '{}' for '{}', downloading it instead: {}", src, pathstring, why)),
+            }
+        }
+        if let Some(ref c) = cache {
+            if c.path(expected_hash).map_or(false, |p| p.exists()) {
+                match copy_from_cache(c, &p, expected_hash, &this_prog_path) {
+                    Ok(_) => {
+                        from_cache += 1;
+                        if at_real_path {
+                            local_by_hash.insert(expected_hash.clone(), pathstring.clone());
+                        }
+                        continue;
+                    },
+                    Err(why) => download_progbar.println(format!("Unable to use the cached copy of '{}', downloading it instead: {}", pathstring, why)),
+                }
+            }
+        }
+        if let Err(why) = get_file(upstream, &cp_path, &pathstring, &p, hashes.get(&pathstring), expected_hash, &this_prog_path, &download_progbar) {
+            if upstream.bundle.is_some() {
+                println!("Unable to update '{}': {}.", pathstring, why);
+            } else {
+                println!("Error retrieving file '{}': {}. Please try updating again later.", pathstring, why);
             }
-        } // end the file write scope
+            return false;
+        }
+        if let Some(ref c) = cache {
+            if let Err(why) = c.store(&p, expected_hash) {
+                download_progbar.println(format!("Warning: {}", why));
+            }
+        }
+        upstream.fetched.insert(expected_hash.clone(), p.clone());
+        if at_real_path {
+            local_by_hash.insert(expected_hash.clone(), pathstring.clone());
+        }
     }// end the pathstring for loop
+    if reused > 0 {
+        println!("Reused {} files already on this computer instead of downloading them.", reused);
+    }
+    if let Some(ref c) = cache {
+        if from_cache > 0 {
+            println!("Got {} files from the content cache instead of downloading them.", from_cache);
+        }
+        let (count, bytes) = c.prune();
+        if count > 0 {
+            println!("Removed {} files ({}) from the content cache to keep it under it's size limit.", count, components::format_size(bytes));
+        }
+    }
+    // files with the merge policy that were already up to date still need a base, so the next change to them can be merged
+    for (pathstring, policy) in &policy_index {
+        let base_path = policy::merge_base_path(&cp_path, pathstring);
+        if *policy == Policy::Merge && !base_path.exists() && hashes.get(pathstring).is_some() && hashes.get(pathstring) == master_manifest.get(pathstring) {
+            let _ = create_dir_all(base_path.parent().unwrap()).and_then(|_| fs::copy(cp_path.join(pathstring), &base_path));
+        }
+    }
     download_progbar.finish_with_message(&format!("downloaded"));
+    // remove what's installed of components that have been disabled, unless the user has changed it
+    let mut removed = 0;
+    for pathstring in &unwanted {
+        let local_hash = match hashes.get(pathstring) {
+            Some(h) if !utils::is_placeholder_hash(h) => h,
+            _ => continue, // not installed, or ignored
+        };
+        let was_installed = master_manifest.get(pathstring) == Some(local_hash) || installed_manifest.as_ref().and_then(|i| i.get(pathstring)) == Some(local_hash);
+        if !was_installed {
+            modified_report.push(format!("{}: kept, since you've changed it, even though it's part of a disabled component", pathstring));
+            continue;
+        }
+        let p = cp_path.join(pathstring);
+        match fs::remove_file(&p) {
+            Ok(_) => {
+                removed += 1;
+                // tidy up directories the component leaves empty; remove_dir fails on anything that isn't
+                let mut dir = p.parent();
+                while let Some(d) = dir {
+                    if d == cp_path.as_path() || fs::remove_dir(d).is_err() {
+                        break;
+                    }
+                    dir = d.parent();
+                }
+            },
+            Err(why) => println!("Unable to remove '{}': {}", pathstring, why),
+        }
+    }
+    if removed > 0 {
+        println!("Removed {} files belonging to disabled components.", removed);
+    }
+    // remember what was installed, so the next update can tell which files the user changed; files that were left as they are keep what was recorded for them before
+    let mut installed_now = wanted_manifest.clone();
+    for pathstring in &not_in_bundle {
+        match installed_manifest.as_ref().and_then(|i| i.get(pathstring)) {
+            Some(hash) => installed_now.insert(pathstring.clone(), hash.clone()),
+            None => installed_now.remove(pathstring),
+        };
+    }
+    if let Err(why) = installed::save(&cp_path, &installed_now) {
+        println!("Warning: {}", why);
+    }
+    if let Ok(ref component_list) = upstream.components {
+        if let Err(why) = installed::save_components(&cp_path, component_list) {
+            println!("Warning: {}", why);
+        }
+    }
     println!("Update completed!");
+    if !modified_report.is_empty() {
+        println!();
+        println!("Not every file was simply replaced:");
+        for line in &modified_report {
+            println!("  {}", line);
+        }
+    }
+    show_changes(&upstream.changelog, &cp_path, old_version.as_ref());
+    true
+}
+
+/// Drop the entries of a downloaded manifest that would write outside the chatpack folder (or otherwise can't be written safely), or whose hashes aren't really hashes, saying which
+///
+/// `case_insensitive` is whether the chatpack folder is on a filesystem that ignores case, from `paths::is_case_insensitive`
+fn safe_manifest (mut hashes: BTreeMap<String, String>, case_insensitive: bool) -> BTreeMap<String, String> {
+    let bad = manifest::remove_unsafe_paths(&mut hashes);
+    if !bad.is_empty() {
+        println!("Warning: skipping {} files in the manifest with paths that aren't safe to write to:", bad.len());
+        for (key, why) in bad {
+            println!("  {:?}: {}", key, why);
+        }
+    }
+    let bad = manifest::remove_bad_hashes(&mut hashes);
+    if !bad.is_empty() {
+        println!("Warning: skipping {} files in the manifest whose hashes aren't valid:", bad.len());
+        for key in bad {
+            println!("  {:?}", key);
+        }
+    }
+    if case_insensitive {
+        for group in manifest::remove_case_collisions(&mut hashes) {
+            println!("Warning: the manifest has files that only differ by case, which can't all exist here; only {} will be updated: {}", group[0], group.join(", "));
+        }
+    }
+    hashes
+}
+
+/// Hash the files under `cp_path` (see `take_snapshot`), with their paths in the same form as those in `reference` so the two can be compared
+fn local_hashes (cp_path: &Path, reference: &BTreeMap<String, String>, case_insensitive: bool) -> BTreeMap<String, String> {
+    let hashes = manifest::canonicalize(take_snapshot(cp_path));
+    if case_insensitive {
+        manifest::match_case(hashes, reference)
+    } else {
+        hashes
+    }
+}
+
+/// Split `master_manifest` into the files the user wants, and the files belonging to optional components they've disabled
+///
+/// If the component list couldn't be retrieved, the one saved by the last update is used, so a network blip can't bring back components the user turned off; without either, that's an error
+fn wanted_files (cp_path: &Path, component_list: &Result<components::Components, String>, master_manifest: &BTreeMap<String, String>) -> Result<(BTreeMap<String, String>, BTreeSet<String>), String> {
+    let disabled = Settings::load(cp_path).disabled_components;
+    let saved;
+    let component_list = match *component_list {
+        Ok(ref c) => c,
+        // with nothing disabled, everything is wanted whatever the list says
+        Err(_) if disabled.is_empty() => return Ok((master_manifest.clone(), BTreeSet::new())),
+        Err(ref why) => match installed::load_components(cp_path) {
+            Some(c) => {
+                println!("Warning: can't retrieve the list of optional components ({}), so the one from the last update is used.", why);
+                saved = c;
+                &saved
+            },
+            None => return Err(format!("Can't retrieve the list of optional components, which is needed to leave out the ones you've disabled: {}. Please try again later.", why)),
+        },
+    };
+    let unwanted = components::disabled_files(component_list, &disabled);
+    let wanted_manifest = master_manifest.iter()
+        .filter(|&(path, _)| !unwanted.contains(path))
+        .map(|(path, hash)| (path.clone(), hash.clone()))
+        .collect();
+    Ok((wanted_manifest, unwanted))
+}
+
+/// Show what an update from `source` would change
+fn status (cp_path: &Path, source: Source) {
+    match read_version(cp_path) {
+        Some(v) => println!("Installed version: {}", v.to_string()),
+        None => println!("Can't tell which version of {} is installed.", TARGET_DIR),
+    }
+    let upstream = match Upstream::fetch(source) {
+        Ok(u) => u,
+        Err(why) => {
+            println!("Can't retrieve the manifest: {}. Please try again later.", why);
+            return;
+        },
+    };
+    let case_insensitive = paths::is_case_insensitive(cp_path);
+    let master_manifest = safe_manifest(upstream.manifest.clone(), case_insensitive);
+    let (wanted_manifest, _) = match wanted_files(cp_path, &upstream.components, &master_manifest) {
+        Ok(w) => w,
+        Err(why) => {
+            println!("{}", why);
+            return;
+        },
+    };
+    let hashes = local_hashes(cp_path, &wanted_manifest, case_insensitive);
+    let diff = match manifest::compare(hashes.clone(), wanted_manifest.clone()) {
+        Ok(d) => d,
+        Err(why) => {
+            println!("{}", why);
+            return;
+        },
+    };
+    let protected = utils::protected_files(cp_path, wanted_manifest.keys());
+    let new_files: Vec<&String> = diff.added.iter().collect();
+    let changed_files: Vec<&String> = diff.modified.iter().filter(|f| !protected.contains(*f)).collect();
+    if new_files.is_empty() && changed_files.is_empty() {
+        println!("Everything is up to date.");
+    } else {
+        println!("An update would get {} new files and {} changed files:", new_files.len(), changed_files.len());
+        for f in new_files {
+            println!("  new: {}", f);
+        }
+        for f in changed_files {
+            println!("  changed: {}", f);
+        }
+    }
+    show_protected(&protected, &hashes, &wanted_manifest, "up to date");
+}
+
+/// Check the files under `cp_path` against what the updater last installed
+fn verify (cp_path: &Path) {
+    let installed_manifest = match installed::load(cp_path) {
+        Some(m) => m,
+        None => {
+            println!("The updater hasn't recorded what it installed yet, so there's nothing to check against; this is done after every update.");
+            return;
+        },
+    };
+    let hashes = local_hashes(cp_path, &installed_manifest, paths::is_case_insensitive(cp_path));
+    let diff = match manifest::compare(hashes.clone(), installed_manifest.clone()) {
+        Ok(d) => d,
+        Err(why) => {
+            println!("{}", why);
+            return;
+        },
+    };
+    let protected = utils::protected_files(cp_path, installed_manifest.keys());
+    let missing: Vec<&String> = diff.added.iter().filter(|f| !protected.contains(*f)).collect();
+    let changed: Vec<&String> = diff.modified.iter().filter(|f| !protected.contains(*f)).collect();
+    if missing.is_empty() && changed.is_empty() {
+        println!("Every file is as the updater installed it.");
+    } else {
+        println!("{} files are missing and {} have changed since the updater installed them:", missing.len(), changed.len());
+        for f in missing {
+            println!("  missing: {}", f);
+        }
+        for f in changed {
+            println!("  changed: {}", f);
+        }
+        println!("Run the updater to restore them.");
+    }
+    show_protected(&protected, &hashes, &installed_manifest, "as installed");
+}
+
+/// List the protected files, and whether each matches `reference` (described by `matching`), has changed, or is missing
+fn show_protected (protected: &BTreeSet<String>, hashes: &BTreeMap<String, String>, reference: &BTreeMap<String, String>, matching: &str) {
+    if protected.is_empty() {
+        return;
+    }
+    println!("Protected files (never overwritten without asking):");
+    for f in protected {
+        let state = match hashes.get(f) {
+            None => "missing",
+            Some(h) if reference.get(f) == Some(h) => matching,
+            Some(_) => "changed",
+        };
+        println!("  {}: {}", state, f);
+    }
+}
+
+/// Hash the files under `cp_path` (skipping ignored ones) to see how they look now
+fn take_snapshot (cp_path: &Path) -> BTreeMap<String, String> {
+    // find all ignored files and directories, so they can be skipped when hashing to save time
+    let mut ignores: BTreeSet<String> = BTreeSet::new();
+    let ignore_rules = utils::IgnoreRules::load(cp_path); // the standard then custom ignore files' rules
+    if !ignore_rules.is_empty() {
+        // walk our current directory recursively and add relative paths of ignored files and dirs
+        ignores.append(&mut utils::ignored_files(cp_path, &ignore_rules));
+    }
+    // the copies kept for merging aren't part of chatpack
+    ignores.insert(MERGE_BASE_DIRNAME.to_string());
+    let max_recursion: Option<usize> = Some(10);
+    println!("Taking a snapshot of how files look now...");
+    // Hash files in `TARGET_DIR` to determine what needs to be updated
+    let hashes: BTreeMap<String, String> = create_hashes(cp_path,
+        ignores,
+        ALGO,
+        max_recursion,
+        true,
+        JOBS,
+        stdout(),
+        &mut stderr()
+    );
+    println!();
+    println!("Done.");
+    hashes
+}
+
+/// Ask the user what to do with the files they've changed that the update would replace
+fn ask_modified_action (user_modified: &[String]) -> ModifiedAction {
+    println!("You've changed these files, and the new version changes them too:");
+    for f in user_modified {
+        println!("  {}", f);
+    }
+    loop {
+        println!("What should happen to your copies? [b]ack them up and install the new versions, [k]eep yours, or keep yours and [s]ave the new versions next to them:");
+        let mut answer = String::new();
+        match io::stdin().read_line(&mut answer) {
+            // with nobody there to answer, leaving the user's files alone is the safe choice
+            Ok(0) | Err(_) => return ModifiedAction::Keep,
+            Ok(_) => (),
+        }
+        if let Some(action) = ModifiedAction::from_string(&answer) {
+            return action;
+        }
+    }
+}
+
+/// Ask the user whether the protected files the update would replace should be overwritten
+fn ask_overwrite_protected (protected: &BTreeSet<String>) -> bool {
+    println!("The new version changes these files you've protected:");
+    for f in protected {
+        println!("  {}", f);
+    }
+    loop {
+        println!("Overwrite them? [y]es or [n]o:");
+        let mut answer = String::new();
+        match io::stdin().read_line(&mut answer) {
+            // with nobody there to answer, leave them alone
+            Ok(0) | Err(_) => return false,
+            Ok(_) => (),
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "y" | "yes" => return true,
+            "n" | "no" => return false,
+            _ => (),
+        }
+    }
+}
+
+/// List the optional components, or enable or disable one of them
+fn components_command (cp_path: &Path, settings: &mut Settings, source: &Source, command: &str, name: Option<&String>) {
+    // a pack that doesn't publish a component list has no components
+    let component_list: components::Components = match load_published(source, COMPONENTS_FILENAME) {
+        Ok(c) => c.unwrap_or_default(),
+        Err(why) => {
+            println!("Can't retrieve the list of components: {}", why);
+            return;
+        },
+    };
+    if command == "components" {
+        if component_list.is_empty() {
+            println!("{} doesn't have any optional components.", TARGET_DIR);
+        }
+        for (name, component) in &component_list {
+            let state = if settings.disabled_components.contains(name) { "disabled" } else { "enabled" };
+            println!("{} ({}, {}): {}", name, state, components::format_size(component.size), component.description);
+        }
+        return;
+    }
+    let name = match name {
+        Some(n) => n,
+        None => {
+            println!("Usage: chatpack-updater {} <component>", command);
+            return;
+        },
+    };
+    if !component_list.contains_key(name) {
+        println!("There's no '{}' component; run `chatpack-updater components` to see them all.", name);
+        return;
+    }
+    if command == "enable" {
+        settings.disabled_components.remove(name);
+    } else {
+        settings.disabled_components.insert(name.clone());
+    }
+    match settings.save(cp_path) {
+        Ok(_) => println!("The {} component is now {}d; it'll be {} the next time you update.", name, command, if command == "enable" { "installed" } else { "removed" }),
+        Err(why) => println!("Unable to save settings: {}", why),
+    }
+}
+
+/// Show the current update channel, or switch to `new_channel` and save it
+fn channel_command (cp_path: &Path, settings: &mut Settings, new_channel: Option<&String>) {
+    let current = settings.channel.clone().unwrap_or_else(|| DEFAULT_CHANNEL.to_string());
+    let new_channel = match new_channel {
+        Some(c) => c,
+        None => {
+            println!("Updates come from the {} channel. The channels are {}.", current, channel_names());
+            return;
+        },
+    };
+    if source::for_channel(new_channel).is_none() {
+        println!("There's no '{}' update channel; the channels are {}.", new_channel, channel_names());
+        return;
+    }
+    settings.channel = Some(new_channel.clone());
+    match settings.save(cp_path) {
+        Ok(_) => println!("Updates will now come from the {} channel; run the updater again to switch.", new_channel),
+        Err(why) => println!("Unable to save the channel: {}", why),
+    }
+}
+
+/// Show the content cache's state, turn it on or off, change it's size limit, or prune it
+fn cache_command (cp_path: &Path, settings: &mut Settings, args: &[String]) {
+    let c = match Cache::from_settings(settings) {
+        Some(c) => c,
+        None => {
+            println!("Can't tell where to keep the content cache; set cache_dir in {}.", SETTINGS_FILENAME);
+            return;
+        },
+    };
+    let message = match args.get(0).map(|a| a.as_str()) {
+        None => {
+            let (count, bytes) = c.usage();
+            println!("The content cache is {} for this mush folder.", if settings.cache { "on" } else { "off" });
+            println!("It's at {}, and holds {} files ({} of {}).", c.dir.display(), count, components::format_size(bytes), components::format_size(c.limit));
+            return;
+        },
+        Some("on") => {
+            settings.cache = true;
+            format!("The content cache is now on; files will be shared through {}.", c.dir.display())
+        },
+        Some("off") => {
+            settings.cache = false;
+            "The content cache is now off for this mush folder.".to_string()
+        },
+        Some("limit") => match args.get(1).and_then(|l| l.parse::<u64>().ok()) {
+            Some(limit) => {
+                settings.cache_limit = Some(limit);
+                format!("The content cache can now hold up to {} megabytes; run `chatpack-updater cache prune` to trim it now.", limit)
+            },
+            None => {
+                println!("Usage: chatpack-updater cache limit <megabytes>");
+                return;
+            },
+        },
+        Some("prune") => {
+            let (count, bytes) = c.prune();
+            println!("Removed {} files ({}) from the content cache.", count, components::format_size(bytes));
+            return;
+        },
+        Some(_) => {
+            println!("Usage: chatpack-updater cache [on|off|limit <megabytes>|prune]");
+            return;
+        },
+    };
+    match settings.save(cp_path) {
+        Ok(_) => println!("{}", message),
+        Err(why) => println!("Unable to save settings: {}", why),
+    }
+}
+
+/// A comma separated list of the update channels
+fn channel_names () -> String {
+    CHANNELS.iter().map(|&(channel, _)| channel).collect::<Vec<&str>>().join(", ")
+}
+
+/// What a source can publish alongside it's manifest to make downloads quicker
+#[derive(Default)]
+struct Extras {
+    patches: patch::PatchIndex, // binary patches between versions of large files
+    compressed: compression::CompressedIndex, // pre-compressed copies of text files
+    objects: objects::ObjectIndex, // files stored by hash
+}
+
+impl Extras {
+    /// Load whichever extras `source` publishes
+    fn load(source: &Source) -> Extras {
+        Extras {
+            patches: patch::load_index(&source.url(PATCH_INDEX_FILENAME)).unwrap_or_default(),
+            compressed: compression::load_index(&source.url(COMPRESSED_INDEX_FILENAME)).unwrap_or_default(),
+            objects: objects::load_index(&source.url(OBJECT_INDEX_FILENAME)).unwrap_or_default(),
+        }
+    }
+}
+
+/// Get the new version of `pathstring` into `p`, copying it from a file already fetched (or unpacked from a bundle) if there is one, and otherwise with `fetch_file`
+fn get_file (upstream: &Upstream, cp_path: &Path, pathstring: &str, p: &Path, old_hash: Option<&String>, expected_hash: &str, this_prog_path: &Path, progbar: &ProgressBar) -> Result<(), String> {
+    if let Some(src) = upstream.fetched.get(expected_hash) {
+        match reuse_local(src, p, expected_hash, false, this_prog_path) {
+            Ok(_) => return Ok(()),
+            Err(why) => progbar.println(format!("Unable to copy '{}' from {}: {}", pathstring, src.display(), why)),
+        }
+    }
+    if let Some(ref info) = upstream.bundle {
+        return Err(format!("it isn't in the bundle, which only holds what changed since version {}", info.from));
+    }
+    if let Some(ref repo) = upstream.git {
+        return fetch_from_git(repo, &upstream.source.git_ref, pathstring, p, expected_hash, this_prog_path);
+    }
+    fetch_file(&upstream.client, &upstream.source, cp_path, pathstring, p, old_hash, expected_hash, &upstream.extras, this_prog_path, progbar)
+}
+
+/// Get `pathstring` as of `commit` out of a git repository into `p`, checking it against the manifest just like a download
+fn fetch_from_git (repo: &GitRepo, commit: &str, pathstring: &str, p: &Path, expected_hash: &str, this_prog_path: &Path) -> Result<(), String> {
+    create_dir_all(p.parent().unwrap()).map_err(|why| why.to_string())?;
+    let part = part_path(p);
+    repo.write_to(commit, &format!("{}/{}", TARGET_DIR, pathstring), &part)?;
+    install_part(&part, p, expected_hash, this_prog_path)
+}
+
+/// Load one of the json files published next to the manifest, over the web or straight out of a git repository; None if it isn't published
+fn load_published<T: DeserializeOwned> (source: &Source, name: &str) -> Result<Option<T>, String> {
+    match source.git_repo {
+        Some(ref location) => {
+            let repo = GitRepo::open(location)?;
+            let commit = repo.resolve(&source.git_ref)?;
+            if repo.has(&commit, name) { repo.read_json(&commit, name).map(Some) } else { Ok(None) }
+        },
+        None => utils::load_optional_json(&source.url(name)),
+    }
+}
+
+/// Load the index of published versions; in a git repository it's built from the history instead, so every version ever published can be installed
+fn load_versions (source: &Source) -> Result<versions::VersionIndex, String> {
+    match source.git_repo {
+        Some(ref location) => {
+            let repo = GitRepo::open(location)?;
+            repo.version_index(&repo.resolve(&source.git_ref)?)
+        },
+        None => versions::load(&source.url(VERSIONS_FILENAME)),
+    }
+}
+
+/// Get the new version of `pathstring` into `p`: by patching the local copy (which hashes to `old_hash`) if there's a patch for it, then from a pre-compressed copy if there's one, and otherwise in full (by hash if the source publishes objects)
+fn fetch_file (client: &reqwest::Client, source: &Source, cp_path: &Path, pathstring: &str, p: &Path, old_hash: Option<&String>, expected_hash: &str, extras: &Extras, this_prog_path: &Path, progbar: &ProgressBar) -> Result<(), String> {
+    // patches are applied to the local copy in place, so they're no use when the new version is going somewhere else
+    if let Some(old_hash) = old_hash {
+        if p == cp_path.join(pathstring) && patch::has_patch(&extras.patches, old_hash, expected_hash) {
+            let max_len = patch::target_size(&extras.patches, expected_hash).unwrap_or(0);
+            match update_with_patch(client, source, cp_path, pathstring, old_hash, expected_hash, max_len, this_prog_path) {
+                Ok(_) => return Ok(()),
+                Err(why) => progbar.println(format!("Unable to patch '{}', downloading it instead: {}", pathstring, why)),
+            }
+        }
+    }
+    // text files may have an up to date pre-compressed copy, which is quicker to download
+    if extras.compressed.get(pathstring).map(|h| h.as_str()) == Some(expected_hash) {
+        let url = source.url(&format!("{}/{}{}", COMPRESSED_DIRNAME, utils::percent_encode_pathstring(pathstring), GZIP_SUFFIX));
+        match download_file(client, &url, p, expected_hash, true, this_prog_path) {
+            Ok(_) => return Ok(()),
+            Err(why) => progbar.println(format!("Unable to use the compressed copy of '{}', downloading it in full instead: {}", pathstring, why)),
+        }
+    }
+    // objects are stored by hash, so their urls never change and can be cached forever
+    if extras.objects.contains(expected_hash) {
+        match download_file(client, &source.object_url(expected_hash), p, expected_hash, false, this_prog_path) {
+            Ok(_) => return Ok(()),
+            Err(why) => progbar.println(format!("Unable to get '{}' by it's hash, downloading it by path instead: {}", pathstring, why)),
+        }
+    }
+    download_file(client, &source.file_url(pathstring, expected_hash), p, expected_hash, false, this_prog_path)
+}
+
+/// Update `pathstring`, which has the merge policy, by merging the upstream changes since the last update into the local copy
+///
+/// Returns a line for the report at the end of the update if the result is anything other than the new version
+fn update_merged (upstream: &Upstream, cp_path: &Path, pathstring: &str, expected_hash: &str, this_prog_path: &Path, progbar: &ProgressBar) -> Result<Option<String>, String> {
+    let p = cp_path.join(pathstring);
+    let base_path = policy::merge_base_path(cp_path, pathstring);
+    let old_base = fs::read_to_string(&base_path).ok();
+    // the new version is fetched to where the base is kept, since it's what the next update merges against
+    get_file(upstream, cp_path, pathstring, &base_path, None, expected_hash, this_prog_path, progbar)?;
+    if !p.exists() {
+        fs::copy(&base_path, &p).map_err(|why| why.to_string())?;
+        return Ok(None);
+    }
+    let new_copy = installed::new_copy_path(&p);
+    match (old_base, fs::read_to_string(&p), fs::read_to_string(&base_path)) {
+        (Some(base), Ok(ours), Ok(theirs)) => {
+            if ours == base {
+                // the user hasn't changed it, so there's nothing to merge
+                fs::write(&p, theirs).map_err(|why| why.to_string())?;
+                return Ok(None);
+            }
+            match policy::merge(&base, &ours, &theirs) {
+                Ok(merged) => {
+                    fs::write(&p, merged).map_err(|why| why.to_string())?;
+                    Ok(Some(format!("{}: your changes were merged into the new version", pathstring)))
+                },
+                Err(conflicted) => {
+                    fs::write(&new_copy, conflicted).map_err(|why| why.to_string())?;
+                    Ok(Some(format!("{}: your changes conflict with the new version, so your copy was kept; the merge, with the conflicts marked, is in {}", pathstring, new_copy.display())))
+                },
+            }
+        },
+        // there's no base to merge against (or it isn't text), so leave it to the user
+        _ => {
+            fs::copy(&base_path, &new_copy).map_err(|why| why.to_string())?;
+            Ok(Some(format!("{}: your copy was kept, since there was nothing to merge it with; the new version was saved as {}", pathstring, new_copy.display())))
+        },
+    }
+}
+
+/// Put a copy of `src`, a local file that should hash to `expected_hash`, at `p`; with `move_it` set, `src` is moved there instead
+fn reuse_local (src: &Path, p: &Path, expected_hash: &str, move_it: bool, this_prog_path: &Path) -> Result<(), String> {
+    create_dir_all(p.parent().unwrap()).map_err(|why| why.to_string())?;
+    if move_it {
+        // there's no copy to check, so check the original hasn't changed since it was hashed
+        if checksums::hash_file(src, ALGO) != expected_hash {
+            return Err("it's changed since it was checked".to_string());
+        }
+        return rename(src, p).map_err(|why| why.to_string());
+    }
+    let part = part_path(p);
+    fs::copy(src, &part).map_err(|why| why.to_string())?;
+    install_part(&part, p, expected_hash, this_prog_path)
+}
+
+/// Put the cached file with the given hash at `p`
+fn copy_from_cache (cache: &Cache, p: &Path, expected_hash: &str, this_prog_path: &Path) -> Result<(), String> {
+    create_dir_all(p.parent().unwrap()).map_err(|why| why.to_string())?;
+    let part = part_path(p);
+    cache.copy_to(expected_hash, &part)?;
+    install_part(&part, p, expected_hash, this_prog_path).map_err(|why| {
+        // a cached file that's been damaged is no use to anyone
+        if let Some(cached) = cache.path(expected_hash) {
+            let _ = fs::remove_file(cached);
+        }
+        why
+    })
+}
+
+/// Update `pathstring` by downloading a patch from the local copy (which hashes to `old_hash`) and applying it
+///
+/// The patched file only replaces the local one if it hashes to `new_hash`; `max_len` is the size the patch index says it is
+fn update_with_patch (client: &reqwest::Client, source: &Source, cp_path: &Path, pathstring: &str, old_hash: &str, new_hash: &str, max_len: u64, this_prog_path: &Path) -> Result<(), String> {
+    let url = source.url(&format!("{}/{}", PATCHES_DIRNAME, patch::patch_name(old_hash, new_hash)));
+    let resp = client.get(&url).send().map_err(|why| why.to_string())?;
+    if !resp.status().is_success() {
+        return Err(format!("ChatMUD's git returned status code {}", resp.status()));
+    }
+    // a patch bigger than the file it makes is no use, so there's no point reading more than that
+    let mut patch_bytes: Vec<u8> = vec![];
+    compression::decoded_body(resp).take(max_len + 1).read_to_end(&mut patch_bytes).map_err(|why| why.to_string())?;
+    if patch_bytes.len() as u64 > max_len {
+        return Err(format!("the patch is bigger than the {} bytes the patch index says the file is", max_len));
+    }
+    let p = cp_path.join(pathstring);
+    let old = fs::read(&p).map_err(|why| why.to_string())?;
+    let new = patch::apply_patch(&old, &patch_bytes, max_len)?;
+    // write the result next to the real file, and only move it into place once it's been verified
+    let part = part_path(&p);
+    fs::write(&part, &new).map_err(|why| why.to_string())?;
+    install_part(&part, &p, new_hash, this_prog_path)
+}
+
+/// Download `url` to `p`, gunzipping it on the way if `gzipped` is set (for pre-compressed copies)
+///
+/// The download is written next to `p`, and only replaces it once the decompressed contents hash to `expected_hash`
+fn download_file (client: &reqwest::Client, url: &str, p: &Path, expected_hash: &str, gzipped: bool, this_prog_path: &Path) -> Result<(), String> {
+    let resp = client.get(url).send().map_err(|why| why.to_string())?;
+    // We have a valid response; check it's status code
+    if !resp.status().is_success() {
+        return Err(format!("ChatMUD's git returned status code {}", resp.status()));
+    }
+    // since open won't create intervening directories, run create_dir_all on path.parent to create any directories up the file that don't exist
+    create_dir_all(&p.parent().unwrap()).map_err(|why| why.to_string())?;
+    let part = part_path(p);
+    {
+        let mut file = match OpenOptions::new().create(true).write(true).truncate(true).open(&part) {
+            Ok(file) => file,
+            Err(why) => return Err(format!("Unable to open file '{}': {}", part.display(), why)),
+        };
+        let mut body = compression::decoded_body(resp);
+        if gzipped {
+            body = compression::gunzip_reader(body);
+        }
+        // copy the http response (which is the file) to the opened file
+        if let Err(why) = io::copy(&mut body, &mut file) {
+            let _ = fs::remove_file(&part);
+            return Err(why.to_string());
+        }
+    }
+    install_part(&part, p, expected_hash, this_prog_path)
+}
+
+/// The path a file is written to while it's being downloaded or patched, before it's been verified
+fn part_path (p: &Path) -> PathBuf {
+    let mut part_name = p.file_name().unwrap().to_os_string();
+    part_name.push(".part");
+    p.with_file_name(part_name)
+}
+
+/// Move a finished download (`part`) into place at `p`, as long as it hashes to `expected_hash`
+fn install_part (part: &Path, p: &Path, expected_hash: &str, this_prog_path: &Path) -> Result<(), String> {
+    if checksums::hash_file(part, ALGO) != expected_hash {
+        let _ = fs::remove_file(part);
+        return Err("the downloaded file doesn't match the manifest".to_string());
+    }
+    // check if the file to be updated is actually this program (when updating several installs, it might only share it's name)
+    if this_prog_path.file_name() == p.file_name() && fs::canonicalize(p).ok().map_or(false, |p| fs::canonicalize(this_prog_path).ok() == Some(p)) {
+        // rename this program, passing a '.old' suffix so the new version can be put in place
+        rename(this_prog_path, this_prog_path.with_extension("old")).expect("Error renaming the updater.");
+    }
+    rename(part, p).map_err(|why| why.to_string())
+}
+
+/// Read the installed version of chatpack from it's version file, if there is one
+fn read_version (cp_path: &Path) -> Option<Version> {
+    let mut str_ver = String::new();
+    File::open(cp_path.join(VERSION_FILENAME)).and_then(|mut f| f.read_to_string(&mut str_ver)).ok()?;
+    Version::parse(&str_ver).ok()
+}
+
+/// Print the changelog entries between `old_version` and the version that was just installed, and save them to a file the user can open later
+fn show_changes (log: &changelog::Changelog, cp_path: &Path, old_version: Option<&Version>) {
+    let new_version = match read_version(cp_path) {
+        Some(v) => v,
+        None => return,
+    };
+    // not every copy of chatpack publishes a changelog, in which case this is empty
+    let entries = changelog::entries_between(log, old_version, &new_version);
+    if entries.is_empty() {
+        return;
+    }
+    let text = format!("Changes in {} {}:\n\n{}", TARGET_DIR, new_version.to_string(), changelog::format_entries(&entries));
+    println!();
+    print!("{}", text);
+    let changes_path = cp_path.join(LOCAL_CHANGES_FILENAME);
+    match File::create(&changes_path).and_then(|mut f| f.write_all(text.as_bytes())) {
+        Ok(_) => println!("These changes have been saved to '{}'.", changes_path.display()),
+        Err(why) => println!("Unable to save the list of changes to '{}': {}", changes_path.display(), why),
+    }
 }
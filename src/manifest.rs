@@ -0,0 +1,301 @@
+// loading and comparing hash manifests
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use checksums::ops::{compare_hashes, CompareResult, CompareFileResult};
+use std::io::Read;
+use crate::utils::{self, is_placeholder_hash};
+use crate::compression;
+use crate::paths;
+use crate::constants::GZIP_SUFFIX;
+
+/// A hash manifest: paths relative to the chatpack directory, mapped to their hashes
+pub type Hashes = BTreeMap<String, String>;
+
+/// The result of comparing one manifest against another
+#[derive(Debug, Default, Serialize)]
+pub struct ManifestDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+    #[serde(skip)]
+    pub ignored: Vec<String>,
+}
+
+/// How a `ManifestDiff` should be written out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiffFormat {
+    Text,
+    Json,
+    Markdown,
+}
+
+impl DiffFormat {
+    pub fn from_string(format: &str) -> Option<DiffFormat> {
+        match format.trim().to_lowercase().as_str() {
+            "text" | "txt" => Some(DiffFormat::Text),
+            "json" => Some(DiffFormat::Json),
+            "markdown" | "md" => Some(DiffFormat::Markdown),
+            _ => None,
+        }
+    }
+}
+
+/// Load a manifest from either a local file or a URL
+pub fn load (location: &str) -> Result<Hashes, String> {
+    if utils::is_url(location) {
+        fetch(&compression::http_client(), location)
+    } else {
+        load_file(Path::new(location))
+    }
+}
+
+/// Download a manifest, preferring a pre-compressed copy if one has been published next to it
+pub fn fetch (client: &reqwest::Client, url: &str) -> Result<Hashes, String> {
+    let gz_url = format!("{}{}", url, GZIP_SUFFIX);
+    if let Ok(resp) = client.get(&gz_url).send() {
+        if resp.status().is_success() {
+            let mut bytes = vec![];
+            compression::decoded_body(resp).read_to_end(&mut bytes).map_err(|why| format!("Can't retrieve '{}': {}", gz_url, why))?;
+            // the hashes in the manifest are only ever checked against the decompressed copy
+            let bytes = compression::gunzip(&bytes)?;
+            return serde_json::from_slice(&bytes).map(canonicalize).map_err(|why| format!("Error parsing manifest '{}': {}", gz_url, why));
+        }
+    }
+    utils::fetch_json(client, url).map(canonicalize)
+}
+
+/// Load a manifest from a file on disk
+pub fn load_file (path: &Path) -> Result<Hashes, String> {
+    let file = File::open(path).map_err(|why| format!("Can't open manifest '{}': {}", path.display(), why))?;
+    serde_json::from_reader(file).map(canonicalize).map_err(|why| format!("Error parsing manifest '{}': {}", path.display(), why))
+}
+
+/// Rewrite every key of `hashes` in it's canonical form (see `paths::canonical`), so manifests built on any system compare the same
+pub fn canonicalize (hashes: Hashes) -> Hashes {
+    hashes.into_iter().map(|(key, hash)| (paths::canonical(&key), hash)).collect()
+}
+
+/// On a case-insensitive filesystem, rename the keys of `local` that only differ by case from a key in `reference` to match it, so the two compare as the same file
+pub fn match_case (local: Hashes, reference: &Hashes) -> Hashes {
+    let folded: BTreeMap<String, &String> = reference.keys().map(|k| (k.to_lowercase(), k)).collect();
+    let mut matched = Hashes::new();
+    for (key, hash) in local {
+        let key = match folded.get(&key.to_lowercase()) {
+            Some(reference_key) => (*reference_key).clone(),
+            None => key,
+        };
+        matched.insert(key, hash);
+    }
+    matched
+}
+
+/// Take out all but the first of each group of entries of `hashes` that only differ by case, returning the groups
+///
+/// On a case-insensitive filesystem those entries are all the same file, so it could never match more than one of them
+pub fn remove_case_collisions (hashes: &mut Hashes) -> Vec<Vec<String>> {
+    let collisions = paths::case_collisions(hashes.keys());
+    for group in &collisions {
+        for key in &group[1..] {
+            hashes.remove(key);
+        }
+    }
+    collisions
+}
+
+/// Compare an old manifest (for instance, hashes of what's on disk) against a new one (what it should look like)
+///
+/// This is the comparison the updater uses to decide what to download; added files are in `new` but not `old`, removed ones are the reverse. Manifests hashed with different algorithms can't be compared, and give an error
+pub fn compare (old: Hashes, new: Hashes) -> Result<ManifestDiff, String> {
+    let mut diff = ManifestDiff::default();
+    // files the new manifest ignores have no hash to fetch them by; left in, they'd count as added wherever they're missing
+    // (placeholders in `old` stay, so a file ignored locally isn't counted as modified)
+    let new: Hashes = new.into_iter().filter(|(_, h)| !is_placeholder_hash(h)).collect();
+    // checksums can't compare against an empty set of hashes, so handle those cases here
+    if old.is_empty() || new.is_empty() {
+        diff.added = new.into_keys().collect();
+        diff.removed = old.into_iter().filter(|(_, h)| !is_placeholder_hash(h)).map(|(f, _)| f).collect();
+        return Ok(diff);
+    }
+    let (cr, fcr) = compare_hashes("", new, old).map_err(|_| "Can't compare the manifests: their hashes are different lengths, so they weren't made with the same algorithm".to_string())?;
+    for r in cr {
+        match r {
+            CompareResult::FileAdded(file) => diff.added.push(file),
+            CompareResult::FileRemoved(file) => diff.removed.push(file),
+            CompareResult::FileIgnored(file) => diff.ignored.push(file),
+        }
+    }
+    for r in fcr {
+        match r {
+            CompareFileResult::FileMatches(_) => (), // nothing to do if files are the same
+            CompareFileResult::FileDiffers {file, ..} => diff.modified.push(file),
+        }
+    }
+    diff.added.sort();
+    diff.removed.sort();
+    diff.modified.sort();
+    diff.ignored.sort();
+    Ok(diff)
+}
+
+/// Take out every entry of `hashes` whose path isn't safe to write to, returning them along with why
+pub fn remove_unsafe_paths (hashes: &mut Hashes) -> Vec<(String, &'static str)> {
+    let bad: Vec<(String, &'static str)> = hashes.keys()
+        .filter_map(|key| paths::unsafe_reason(key).map(|why| (key.clone(), why)))
+        .collect();
+    for &(ref key, _) in &bad {
+        hashes.remove(key);
+    }
+    bad
+}
+
+/// Take out every entry of `hashes` whose hash isn't one (or the placeholder for an ignored file), returning their paths
+///
+/// Hashes name files in the cache and the object layout, so one that isn't really a hash could point anywhere
+pub fn remove_bad_hashes (hashes: &mut Hashes) -> Vec<String> {
+    let bad: Vec<String> = hashes.iter()
+        .filter(|&(_, hash)| !utils::is_valid_hash(hash) && !is_placeholder_hash(hash))
+        .map(|(key, _)| key.clone())
+        .collect();
+    for key in &bad {
+        hashes.remove(key);
+    }
+    bad
+}
+
+/// Returns the top-level directory a manifest path lives under, or an empty string for files directly in the chatpack directory
+pub fn top_level (path: &str) -> &str {
+    match path.find('/') {
+        Some(i) => &path[..i],
+        None => "",
+    }
+}
+
+impl ManifestDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+
+    /// Split this diff up by the top-level directory (sounds, scripts, plugins, etc) each path is under
+    pub fn grouped(&self) -> BTreeMap<String, ManifestDiff> {
+        let mut groups: BTreeMap<String, ManifestDiff> = BTreeMap::new();
+        for f in &self.added {
+            groups.entry(top_level(f).to_string()).or_insert_with(ManifestDiff::default).added.push(f.clone());
+        }
+        for f in &self.removed {
+            groups.entry(top_level(f).to_string()).or_insert_with(ManifestDiff::default).removed.push(f.clone());
+        }
+        for f in &self.modified {
+            groups.entry(top_level(f).to_string()).or_insert_with(ManifestDiff::default).modified.push(f.clone());
+        }
+        groups
+    }
+
+    /// Render this diff in the given format, optionally grouped by top-level directory
+    pub fn format(&self, format: DiffFormat, group: bool) -> String {
+        if format == DiffFormat::Json {
+            let j = if group {
+                serde_json::to_string_pretty(&self.grouped())
+            } else {
+                serde_json::to_string_pretty(self)
+            };
+            return j.expect("Couldn't create a json representation of the manifest diff.");
+        }
+        if self.is_empty() {
+            return "No differences.\n".to_string();
+        }
+        let mut out = String::new();
+        if group {
+            for (dir, diff) in self.grouped() {
+                let name = if dir.is_empty() { "(top level)".to_string() } else { dir };
+                match format {
+                    DiffFormat::Markdown => out.push_str(&format!("## {}\n\n", name)),
+                    _ => out.push_str(&format!("{}:\n", name)),
+                }
+                diff.write_sections(&mut out, format, 1);
+            }
+        } else {
+            self.write_sections(&mut out, format, 0);
+        }
+        out
+    }
+
+    fn write_sections(&self, out: &mut String, format: DiffFormat, depth: usize) {
+        let sections = [("Added", &self.added), ("Removed", &self.removed), ("Modified", &self.modified)];
+        let indent = "  ".repeat(depth);
+        for &(title, files) in sections.iter() {
+            if files.is_empty() {
+                continue;
+            }
+            match format {
+                DiffFormat::Markdown => {
+                    out.push_str(&format!("{} {} ({})\n\n", "#".repeat(depth + 2), title, files.len()));
+                    for f in files {
+                        out.push_str(&format!("* `{}`\n", f));
+                    }
+                    out.push('\n');
+                },
+                _ => {
+                    out.push_str(&format!("{}{} ({}):\n", indent, title, files.len()));
+                    for f in files {
+                        out.push_str(&format!("{}  {}\n", indent, f));
+                    }
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashes (entries: &[(&str, &str)]) -> Hashes {
+        entries.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn placeholder_entries_are_never_added () {
+        let old = hashes(&[("a.lua", "AAAA")]);
+        let new = hashes(&[("a.lua", "AAAA"), ("ignored.lua", "----")]);
+        let diff = compare(old, new).unwrap();
+        assert!(diff.added.is_empty());
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn placeholder_only_manifests_add_nothing () {
+        let diff = compare(Hashes::new(), hashes(&[("ignored.lua", "----")])).unwrap();
+        assert!(diff.added.is_empty());
+    }
+
+    #[test]
+    fn files_ignored_locally_are_not_modified () {
+        let old = hashes(&[("a.lua", "AAAA"), ("mine.lua", "----")]);
+        let new = hashes(&[("a.lua", "BBBB"), ("mine.lua", "CCCC"), ("b.lua", "DDDD")]);
+        let diff = compare(old, new).unwrap();
+        assert_eq!(diff.modified, vec!["a.lua".to_string()]);
+        assert_eq!(diff.added, vec!["b.lua".to_string()]);
+        assert_eq!(diff.ignored, vec!["mine.lua".to_string()]);
+    }
+
+    #[test]
+    fn hashes_of_different_lengths_are_an_error () {
+        assert!(compare(hashes(&[("a.lua", "AAAA")]), hashes(&[("a.lua", "BB")])).is_err());
+    }
+
+    #[test]
+    fn entries_whose_hashes_arent_hashes_are_removed () {
+        let good = "0123456789ABCDEF".repeat(8);
+        let mut manifest = hashes(&[
+            ("good.lua", &good), ("ignored.lua", "----"),
+            ("path.lua", "/home/u/.ssh/id_rsa"), ("up.lua", "../../.."), ("lower.lua", &good.to_lowercase()),
+            ("short.lua", "ABCD"), ("wide.lua", "ééé"),
+        ]);
+        let mut bad = remove_bad_hashes(&mut manifest);
+        bad.sort();
+        assert_eq!(bad, vec!["lower.lua", "path.lua", "short.lua", "up.lua", "wide.lua"]);
+        assert_eq!(manifest.keys().collect::<Vec<_>>(), vec!["good.lua", "ignored.lua"]);
+    }
+}
@@ -0,0 +1,43 @@
+// the content-addressed layout: every published file stored under it's hash, so it's url never changes and identical files are only stored once
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use crate::constants::OBJECTS_DIRNAME;
+use crate::utils;
+
+/// The object index: the hash of every object that's been published
+pub type ObjectIndex = BTreeSet<String>;
+
+/// The name of the object holding a file with the given hash, relative to the objects directory; the first two characters make a subdirectory, to keep directories small
+///
+/// None if `hash` isn't hex, since then it could name something outside the objects directory
+pub fn object_name (hash: &str) -> Option<String> {
+    if hash.len() < 3 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(format!("{}/{}", &hash[..2], &hash[2..]))
+}
+
+/// Where the object holding a file with the given hash goes under `root` (the repository), if `hash` can name one
+pub fn object_path (root: &Path, hash: &str) -> Option<PathBuf> {
+    Some(root.join(OBJECTS_DIRNAME).join(object_name(hash)?))
+}
+
+/// Load an object index from either a local file or a URL
+pub fn load_index (location: &str) -> Result<ObjectIndex, String> {
+    utils::load_json(location)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_hex_hashes_name_objects () {
+        assert_eq!(object_name("ABCDEF"), Some("AB/CDEF".to_string()));
+        assert_eq!(object_name("../../etc/passwd"), None);
+        assert_eq!(object_name("/home/u/.ssh/id_rsa"), None);
+        assert_eq!(object_name("ééé"), None);
+        assert_eq!(object_name("AB"), None);
+    }
+}
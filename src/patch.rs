@@ -0,0 +1,107 @@
+// binary patches between two versions of a file, so large files don't have to be downloaded in full for small changes
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use crate::utils;
+
+/// What the patch index records about each file patches lead to
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PatchTarget {
+    pub size: u64, // the size of the file, in bytes, so a patch claiming to make anything bigger can be refused
+    pub bases: BTreeSet<String>, // the hashes there are patches from
+}
+
+/// The patch index: each target hash mapped to what there are patches from
+///
+/// A patch from `old` to `new` lives at `<patches dir>/<old>/<new>`
+pub type PatchIndex = BTreeMap<String, PatchTarget>;
+
+/// Load a patch index from either a local file or a URL
+pub fn load_index (location: &str) -> Result<PatchIndex, String> {
+    utils::load_json(location)
+}
+
+/// Returns true if the index has a patch that turns a file hashing to `old_hash` into one hashing to `new_hash`
+pub fn has_patch (index: &PatchIndex, old_hash: &str, new_hash: &str) -> bool {
+    index.get(new_hash).map_or(false, |target| target.bases.contains(old_hash))
+}
+
+/// The size the index says the file hashing to `new_hash` is, if there are patches to it
+pub fn target_size (index: &PatchIndex, new_hash: &str) -> Option<u64> {
+    index.get(new_hash).map(|target| target.size)
+}
+
+/// The location of a patch relative to the patches directory (or base patch URL)
+pub fn patch_name (old_hash: &str, new_hash: &str) -> String {
+    format!("{}/{}", old_hash, new_hash)
+}
+
+/// Where the patch from `old_hash` to `new_hash` lives on disk, under `patches_dir`
+pub fn patch_path (patches_dir: &Path, old_hash: &str, new_hash: &str) -> PathBuf {
+    patches_dir.join(old_hash).join(new_hash)
+}
+
+/// Create a patch that turns `old` into `new`
+///
+/// Patches are the length of the new file (as a little-endian u64) followed by a bsdiff stream, all gzipped
+pub fn make_patch (old: &[u8], new: &[u8]) -> Result<Vec<u8>, String> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(&(new.len() as u64).to_le_bytes()).map_err(|why| why.to_string())?;
+    bsdiff::diff::diff(old, new, &mut encoder).map_err(|why| format!("Can't create patch: {}", why))?;
+    encoder.finish().map_err(|why| format!("Can't compress patch: {}", why))
+}
+
+/// Apply a patch created by `make_patch` to `old`, returning the new file's contents
+///
+/// The patch is refused if it says the new file is bigger than `max_len` bytes, so a damaged one can't use up all the memory there is
+pub fn apply_patch (old: &[u8], patch: &[u8], max_len: u64) -> Result<Vec<u8>, String> {
+    let mut decoder = GzDecoder::new(Cursor::new(patch));
+    let mut len_bytes = [0u8; 8];
+    decoder.read_exact(&mut len_bytes).map_err(|why| format!("Patch is corrupt: {}", why))?;
+    let len = u64::from_le_bytes(len_bytes);
+    if len > max_len {
+        return Err(format!("Patch is corrupt: it makes a file of {} bytes, but the patch index says it should be {}", len, max_len));
+    }
+    let mut new = vec![0u8; len as usize];
+    bsdiff::patch::patch(old, &mut decoder, &mut new).map_err(|why| format!("Can't apply patch: {}", why))?;
+    Ok(new)
+}
+
+/// Remove patches (both from the index and from disk) whose target isn't in `wanted_hashes` anymore, since nobody can use them
+pub fn prune (patches_dir: &Path, index: &mut PatchIndex, wanted_hashes: &BTreeSet<&String>) {
+    let stale: Vec<String> = index.keys().filter(|h| !wanted_hashes.contains(h)).cloned().collect();
+    for new_hash in stale {
+        for old_hash in index.remove(&new_hash).map(|target| target.bases).unwrap_or_default() {
+            let p = patch_path(patches_dir, &old_hash, &new_hash);
+            let _ = fs::remove_file(&p);
+            // clean up the base hash's directory once it's empty; this fails (harmlessly) if it isn't
+            let _ = fs::remove_dir(p.parent().unwrap());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patches_round_trip () {
+        let old = b"the quick brown fox".to_vec();
+        let new = b"the quick red fox jumps".to_vec();
+        let p = make_patch(&old, &new).unwrap();
+        assert_eq!(apply_patch(&old, &p, new.len() as u64).unwrap(), new);
+    }
+
+    #[test]
+    fn patches_bigger_than_the_index_says_are_refused () {
+        let old = b"old".to_vec();
+        let p = make_patch(&old, &[0u8; 100]).unwrap();
+        assert!(apply_patch(&old, &p, 99).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+// rules for the paths (manifest keys) files are published under
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use unicode_normalization::UnicodeNormalization;
+
+/// Return `key` in the canonical form manifest keys are kept in: / between components, and unicode NFC normalized
+pub fn canonical (key: &str) -> String {
+    key.replace('\\', "/").nfc().collect()
+}
+
+/// Returns true if the filesystem `dir` is on treats names that differ by case as the same file
+pub fn is_case_insensitive (dir: &Path) -> bool {
+    let lower = dir.join(format!("chatpack-case-test-{}.tmp", std::process::id()));
+    let upper = dir.join(format!("CHATPACK-CASE-TEST-{}.TMP", std::process::id()));
+    if fs::write(&lower, b"").is_err() {
+        // can't tell, so assume it's whatever's usual here
+        return cfg!(any(windows, target_os = "macos"));
+    }
+    let insensitive = upper.exists();
+    let _ = fs::remove_file(&lower);
+    insensitive
+}
+
+/// Names Windows reserves for devices, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "con", "prn", "aux", "nul",
+    "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9",
+    "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// Returns true if `name` (one component of a path) is a Windows device name, like `aux` or `com1.txt`
+pub fn is_reserved_name (name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or("").trim_end_matches(|c| c == ' ' || c == '.');
+    RESERVED_NAMES.contains(&stem.to_lowercase().as_str())
+}
+
+/// If `key` isn't safe to write to under the chatpack folder, return why
+///
+/// A safe key is relative, uses / between components, and can't reach outside the folder it's joined to
+pub fn unsafe_reason (key: &str) -> Option<&'static str> {
+    if key.is_empty() {
+        return Some("it's empty");
+    }
+    if key.contains('\0') {
+        return Some("it contains a NUL byte");
+    }
+    if key.contains('\\') {
+        return Some("it contains a backslash");
+    }
+    if key.starts_with('/') {
+        return Some("it's an absolute path");
+    }
+    // also rules out drive prefixes like c: and alternate data streams
+    if key.contains(':') {
+        return Some("it contains a colon, as in a drive prefix");
+    }
+    for component in key.split('/') {
+        match component {
+            "" => return Some("it has an empty component"),
+            "." | ".." => return Some("it has a . or .. component"),
+            c if is_reserved_name(c) => return Some("it uses a name Windows reserves for devices"),
+            _ => (),
+        }
+    }
+    None
+}
+
+/// The longest path Windows handles without special treatment
+const MAX_PATH: usize = 260;
+
+/// How much of `MAX_PATH` to leave for the folder a player installs into, like `C:\Users\<name>\Documents\MUSHclient\`, since the limit applies to the whole path rather than just the part in the manifest
+const INSTALL_PREFIX_MARGIN: usize = 100;
+
+/// Check `key` against Windows' filename rules, returning a description of each problem
+pub fn portability_problems (key: &str) -> Vec<String> {
+    let mut problems = vec![];
+    for component in key.split('/') {
+        if let Some(c) = component.chars().find(|c| "<>:\"\\|?*".contains(*c) || (*c as u32) < 0x20) {
+            problems.push(format!("'{}' contains {:?}, which Windows doesn't allow in file names", component, c));
+        }
+        if component.ends_with('.') || component.ends_with(' ') {
+            problems.push(format!("'{}' ends with a dot or space, which Windows drops", component));
+        }
+        if is_reserved_name(component) {
+            problems.push(format!("'{}' is a name Windows reserves for devices", component));
+        }
+    }
+    if canonical(key) != key {
+        problems.push("it isn't in canonical form (NFC normalized, with / between components), so it's published as it would be in that form".to_string());
+    }
+    let length = key.encode_utf16().count();
+    if length > MAX_PATH - INSTALL_PREFIX_MARGIN {
+        problems.push(format!("it's {} characters long; with the {} or so the folder it's installed in takes, that's more than the {} Windows allows", length, INSTALL_PREFIX_MARGIN, MAX_PATH));
+    }
+    problems
+}
+
+/// Find paths that only differ by case, which can't both exist on Windows; each group is sorted
+pub fn case_collisions<'a, I: IntoIterator<Item = &'a String>> (keys: I) -> Vec<Vec<String>> {
+    let mut by_folded: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for key in keys {
+        by_folded.entry(key.to_lowercase()).or_insert_with(Vec::new).push(key.clone());
+    }
+    by_folded.into_iter()
+        .map(|(_, mut group)| {
+            group.sort();
+            group
+        })
+        .filter(|group| group.len() > 1)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_paths_are_safe () {
+        assert_eq!(unsafe_reason("file.lua"), None);
+        assert_eq!(unsafe_reason("worlds/plugins/foo.lua"), None);
+    }
+
+    #[test]
+    fn paths_reaching_outside_are_unsafe () {
+        assert_eq!(unsafe_reason(".."), Some("it has a . or .. component"));
+        assert_eq!(unsafe_reason("../escape.lua"), Some("it has a . or .. component"));
+        assert_eq!(unsafe_reason("lib/../../escape.lua"), Some("it has a . or .. component"));
+        assert_eq!(unsafe_reason("/etc/passwd"), Some("it's an absolute path"));
+        assert_eq!(unsafe_reason("lib\\..\\escape.lua"), Some("it contains a backslash"));
+    }
+
+    #[test]
+    fn drive_prefixes_are_unsafe () {
+        assert_eq!(unsafe_reason("C:/Windows/win.ini"), Some("it contains a colon, as in a drive prefix"));
+        assert_eq!(unsafe_reason("c:escape.lua"), Some("it contains a colon, as in a drive prefix"));
+    }
+
+    #[test]
+    fn reserved_names_are_unsafe () {
+        assert_eq!(unsafe_reason("aux.lua"), Some("it uses a name Windows reserves for devices"));
+        assert_eq!(unsafe_reason("sounds/aux/rain.ogg"), Some("it uses a name Windows reserves for devices"));
+        assert_eq!(unsafe_reason("lib/COM1.txt"), Some("it uses a name Windows reserves for devices"));
+        assert_eq!(unsafe_reason("lib/auxiliary.lua"), None);
+    }
+
+    #[test]
+    fn long_paths_leave_room_for_the_install_folder () {
+        let short = format!("sounds/{}.ogg", "a".repeat(100));
+        let long = format!("sounds/{}.ogg", "a".repeat(200));
+        assert!(portability_problems(&short).is_empty());
+        assert_eq!(portability_problems(&long).len(), 1);
+    }
+
+    #[test]
+    fn nul_bytes_and_empty_paths_are_unsafe () {
+        assert_eq!(unsafe_reason("file\0.lua"), Some("it contains a NUL byte"));
+        assert_eq!(unsafe_reason(""), Some("it's empty"));
+        assert_eq!(unsafe_reason("lib//file.lua"), Some("it has an empty component"));
+    }
+}
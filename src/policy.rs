@@ -0,0 +1,96 @@
+// per-file update policies: how the updater treats a file when deciding whether to download it
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use crate::constants::MERGE_BASE_DIRNAME;
+use crate::utils::{self, PathPattern};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+    Always, // always forced back to the upstream copy, even if the user changed it
+    IfMissing, // created if it doesn't exist, then left to the user
+    NeverOverwrite, // created if it doesn't exist; after that, new versions are saved next to it instead
+    Merge, // upstream changes are merged into the user's copy
+}
+
+impl Policy {
+    pub fn from_string(policy: &str) -> Option<Policy> {
+        match policy.trim() {
+            "always" => Some(Policy::Always),
+            "if-missing" => Some(Policy::IfMissing),
+            "never-overwrite" => Some(Policy::NeverOverwrite),
+            "merge" => Some(Policy::Merge),
+            _ => None,
+        }
+    }
+}
+
+/// The policy index: paths that have a policy, mapped to it; paths that aren't in it are updated as normal
+pub type PolicyIndex = BTreeMap<String, Policy>;
+
+/// One line of a policy file: an ignore-style pattern, and the policy it sets
+pub struct Rule {
+    pattern: PathPattern,
+    policy: Policy,
+}
+
+/// Parse a policy file
+///
+/// Policy files use ignore file syntax, split into sections by `[policy]` headers; a path gets the policy of the last pattern that matches it, and a negated pattern takes a policy away again
+pub fn parse_rules (text: &str) -> Result<Vec<Rule>, String> {
+    let mut rules = vec![];
+    for section in utils::parse_pattern_sections(text)? {
+        let policy = match Policy::from_string(&section.header) {
+            Some(p) => p,
+            None => return Err(format!("unknown policy [{}]", section.header)),
+        };
+        rules.extend(section.patterns.into_iter().map(|pattern| Rule {pattern, policy}));
+    }
+    Ok(rules)
+}
+
+/// Load and parse a policy file from disk
+pub fn load_rules_file (path: &Path) -> Result<Vec<Rule>, String> {
+    let mut text = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut text)).map_err(|why| format!("Can't read {}: {}", path.display(), why))?;
+    parse_rules(&text).map_err(|why| format!("{}: {}", path.display(), why))
+}
+
+/// Work out the policy for each of `paths` (relative, / separated) from `rules`
+pub fn build_index<'a, I: IntoIterator<Item = &'a String>> (rules: &[Rule], paths: I) -> PolicyIndex {
+    let mut index = PolicyIndex::new();
+    for path in paths {
+        for rule in rules {
+            if rule.pattern.matches(path) {
+                if rule.pattern.negation {
+                    index.remove(path);
+                } else {
+                    index.insert(path.clone(), rule.policy);
+                }
+            }
+        }
+    }
+    index
+}
+
+/// Load a policy index from either a local file or a URL
+pub fn load_index (location: &str) -> Result<PolicyIndex, String> {
+    utils::load_json(location)
+}
+
+/// Where the updater keeps the last upstream copy of a file with the merge policy, to merge the next upstream changes against
+pub fn merge_base_path (cp_path: &Path, pathstring: &str) -> PathBuf {
+    let mut p = cp_path.join(MERGE_BASE_DIRNAME);
+    p.push(pathstring);
+    p
+}
+
+/// Merge the changes between `base` and `theirs` into `ours`
+///
+/// On a conflict, the error holds the merged text with conflict markers in it
+pub fn merge (base: &str, ours: &str, theirs: &str) -> Result<String, String> {
+    diffy::merge(base, ours, theirs)
+}
@@ -0,0 +1,166 @@
+// a small http server for a chatpack repository, laid out the way updaters expect, so computers on a LAN can update from one that's already got everything
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use url::percent_encoding::percent_decode;
+use crate::constants::{DEFAULT_REF, REF_FILENAME};
+use crate::paths;
+
+/// Serve the files under `root` (the directory holding the manifest) on `address` until the program is stopped
+///
+/// Urls look like the repository's raw file urls with `http://<address>/` as the base: `/<ref>/chatpack.update-manifest`, `/<ref>/chatpack/<path>` and so on. There's only ever one version here, so the only refs served are the branch that's checked out and the pinned commit; anything else (an older commit asked for by a rollback, say) isn't found
+pub fn serve (root: &Path, address: &str) -> Result<(), String> {
+    let listener = TcpListener::bind(address).map_err(|why| format!("Can't listen on {}: {}", address, why))?;
+    let branch = Arc::new(current_branch(root));
+    let root = Arc::new(root.to_path_buf());
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        let (root, branch) = (root.clone(), branch.clone());
+        thread::spawn(move || {
+            if let Err(why) = handle(stream, &root, &branch) {
+                println!("Error answering a request: {}", why);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// The branch checked out in the repository at `root`, or the default one if that can't be told
+fn current_branch (root: &Path) -> String {
+    Command::new("git")
+        .args(&["rev-parse", "--abbrev-ref", "HEAD"])
+        .current_dir(root)
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        .filter(|b| !b.is_empty() && b != "HEAD")
+        .unwrap_or_else(|| DEFAULT_REF.to_string())
+}
+
+/// The refs requests can be served for: `branch`, and the commit updates are pinned to, if they are; the pin's read every time, since it moves with each commit
+fn served_refs (root: &Path, branch: &str) -> Vec<String> {
+    let mut refs = vec![branch.to_string()];
+    if let Ok(pin) = std::fs::read_to_string(root.join(REF_FILENAME)) {
+        refs.push(pin.trim().to_string());
+    }
+    refs
+}
+
+/// The file under `root` a request for `url_path` is for, if it's one that can be served; the ref in the url has to be one of `refs`
+pub fn resolve (root: &Path, url_path: &str, refs: &[String]) -> Option<PathBuf> {
+    let url_path = url_path.split(|c| c == '?' || c == '#').next().unwrap_or("");
+    let decoded = percent_decode(url_path.as_bytes()).decode_utf8().ok()?;
+    let mut parts = decoded.trim_start_matches('/').splitn(2, '/');
+    let git_ref = parts.next()?;
+    if !refs.iter().any(|r| r == git_ref) {
+        return None;
+    }
+    let rest = parts.next()?;
+    if paths::unsafe_reason(rest).is_some() || rest.split('/').any(|c| c == ".git") {
+        return None;
+    }
+    let p = root.join(rest);
+    if p.is_file() { Some(p) } else { None }
+}
+
+/// How long to wait for a client to send it's request before giving up on it
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most of a request (the request line and headers) that's read; anything past it is ignored
+const MAX_REQUEST_SIZE: u64 = 8192;
+
+/// Answer one request
+fn handle (stream: TcpStream, root: &Path, branch: &str) -> io::Result<()> {
+    // a client that never finishes it's request, or sends an endless one, would otherwise hold a thread (and it's memory) forever
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?).take(MAX_REQUEST_SIZE);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers don't change anything, but they have to be read before answering
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    let (method, url_path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let mut stream = stream;
+    if method != "GET" && method != "HEAD" {
+        return respond(&mut stream, "405 Method Not Allowed", None, method == "HEAD");
+    }
+    match resolve(root, url_path, &served_refs(root, branch)).and_then(|p| File::open(p).ok()) {
+        Some(f) => {
+            println!("{} {}", method, url_path);
+            respond(&mut stream, "200 OK", Some(f), method == "HEAD")
+        },
+        None => respond(&mut stream, "404 Not Found", None, method == "HEAD"),
+    }
+}
+
+/// Write a response, with the contents of `file` as the body if there is one
+fn respond (stream: &mut TcpStream, status: &str, file: Option<File>, head_only: bool) -> io::Result<()> {
+    let length = match file {
+        Some(ref f) => f.metadata()?.len(),
+        None => 0,
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nContent-Type: application/octet-stream\r\nConnection: close\r\n\r\n", status, length)?;
+    if let (Some(mut f), false) = (file, head_only) {
+        io::copy(&mut f, stream)?;
+    }
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn only_files_under_the_root_are_served () {
+        let dir = std::env::temp_dir().join(format!("chatpack-serve-test-{}", std::process::id()));
+        let root = dir.join("repo");
+        fs::create_dir_all(root.join("chatpack")).unwrap();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::write(root.join("chatpack/file.lua"), b"").unwrap();
+        fs::write(root.join(".git/config"), b"").unwrap();
+        fs::write(dir.join("secret"), b"").unwrap();
+        let refs = vec!["master".to_string()];
+        assert_eq!(resolve(&root, "/master/chatpack/file.lua", &refs), Some(root.join("chatpack/file.lua")));
+        assert_eq!(resolve(&root, "/master/chatpack/file.lua?x=1", &refs), Some(root.join("chatpack/file.lua")));
+        assert_eq!(resolve(&root, "/master/../secret", &refs), None);
+        assert_eq!(resolve(&root, "/master/%2e%2e/secret", &refs), None);
+        assert_eq!(resolve(&root, "/master/chatpack/%2E%2E/%2e%2e/secret", &refs), None);
+        assert_eq!(resolve(&root, "/master/%2fetc%2fpasswd", &refs), None);
+        assert_eq!(resolve(&root, "/master/.git/config", &refs), None);
+        assert_eq!(resolve(&root, "/master/chatpack/missing.lua", &refs), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_the_branch_and_the_pinned_commit_are_served () {
+        let dir = std::env::temp_dir().join(format!("chatpack-serve-refs-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("chatpack")).unwrap();
+        fs::write(dir.join("chatpack/file.lua"), b"").unwrap();
+        let pin = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(served_refs(&dir, "master"), vec!["master".to_string()]);
+        fs::write(dir.join(REF_FILENAME), format!("{}\n", pin)).unwrap();
+        let refs = served_refs(&dir, "master");
+        assert_eq!(resolve(&dir, "/master/chatpack/file.lua", &refs), Some(dir.join("chatpack/file.lua")));
+        assert_eq!(resolve(&dir, &format!("/{}/chatpack/file.lua", pin), &refs), Some(dir.join("chatpack/file.lua")));
+        assert_eq!(resolve(&dir, "/dev/chatpack/file.lua", &refs), None);
+        assert_eq!(resolve(&dir, "/fedcba9876543210fedcba9876543210fedcba98/chatpack/file.lua", &refs), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,42 @@
+// the updater's saved settings, kept in a json file in the mush folder
+
+use std::collections::BTreeSet;
+use std::path::Path;
+use crate::constants::SETTINGS_FILENAME;
+use crate::utils;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub channel: Option<String>, // the update channel to use; the default channel if not set
+    pub hold: Option<String>, // if set, the version updates are held at instead of moving on to the latest
+    pub disabled_components: BTreeSet<String>, // optional components the user has chosen not to install
+    pub source_url: Option<String>, // the base url updates come from, if not REPO_RAW_URL; a ref and then a path get added to it
+    pub git_repo: Option<String>, // a git repository (a local path or url) to read updates straight out of, instead of over the web
+    pub url_template: Option<String>, // how file urls are built, if not the default; see `DEFAULT_FILE_URL_TEMPLATE`
+    pub cache: bool, // whether to use the content cache shared with other mush folders
+    pub cache_dir: Option<String>, // where the content cache is kept, if not the default; see `cache::default_dir`
+    pub cache_limit: Option<u64>, // how big the content cache can get, in megabytes; `DEFAULT_CACHE_LIMIT_MB` if not set
+}
+
+impl Settings {
+    /// Load the settings saved under `cp_path`, or the defaults if there aren't any
+    pub fn load(cp_path: &Path) -> Settings {
+        let p = cp_path.join(SETTINGS_FILENAME);
+        if !p.exists() {
+            return Settings::default();
+        }
+        match utils::load_json(&p.to_string_lossy()) {
+            Ok(s) => s,
+            Err(why) => {
+                println!("Warning: ignoring saved settings: {}", why);
+                Settings::default()
+            },
+        }
+    }
+
+    /// Save these settings under `cp_path`
+    pub fn save(&self, cp_path: &Path) -> Result<(), String> {
+        utils::save_json(&cp_path.join(SETTINGS_FILENAME), self, "settings")
+    }
+}
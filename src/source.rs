@@ -0,0 +1,170 @@
+// where updates come from: the repository's raw file url, and the git ref (branch or commit) to read files at
+
+use std::io::Read;
+use crate::constants::*;
+use crate::compression;
+use crate::utils;
+use crate::objects;
+use crate::version::Version;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+    pub base_url: String, // raw file url, without a ref; for example https://git.chatmud.com/athlon/chatpack/raw/
+    pub git_ref: String,
+    pub file_template: String, // how file urls are built; see `DEFAULT_FILE_URL_TEMPLATE`
+    pub git_repo: Option<String>, // if set, the manifest and files are read straight out of this git repository (a path or url) at `git_ref`, rather than from `base_url`
+}
+
+impl Default for Source {
+    fn default() -> Source {
+        Source::new(REPO_RAW_URL, DEFAULT_REF)
+    }
+}
+
+impl Source {
+    pub fn new(base_url: &str, git_ref: &str) -> Source {
+        let mut base_url = base_url.to_string();
+        if !base_url.ends_with('/') {
+            base_url.push('/');
+        }
+        Source {base_url, git_ref: git_ref.to_string(), file_template: DEFAULT_FILE_URL_TEMPLATE.to_string(), git_repo: None}
+    }
+
+    /// Return a copy of this source that reads from `git_ref` instead
+    pub fn at_ref(&self, git_ref: &str) -> Source {
+        Source {git_ref: git_ref.to_string(), ..self.clone()}
+    }
+
+    /// Return a copy of this source that reads from a repository (or a mirror of one) at `base_url` instead
+    pub fn at_base(&self, base_url: &str) -> Source {
+        Source {base_url: Source::new(base_url, &self.git_ref).base_url, ..self.clone()}
+    }
+
+    /// Return a copy of this source that reads from the git repository at `location` (a local path or url) instead of over the web
+    pub fn in_git_repo(&self, location: &str) -> Source {
+        Source {git_repo: Some(location.to_string()), ..self.clone()}
+    }
+
+    /// Return a copy of this source that builds file urls from `template`, or an error if the template isn't valid
+    ///
+    /// Templates can use {base}, {ref}, {path} and {hash}, and have to use at least one of {path} or {hash} to tell files apart
+    pub fn with_template(&self, template: &str) -> Result<Source, String> {
+        let names = placeholders(template)?;
+        if let Some(unknown) = names.iter().find(|n| !["base", "ref", "path", "hash", "object"].contains(&n.as_str())) {
+            return Err(format!("the url template has an unknown placeholder {{{}}}", unknown));
+        }
+        if !names.iter().any(|n| n == "path" || n == "hash" || n == "object") {
+            return Err("the url template has to use {path}, {hash} or {object}".to_string());
+        }
+        Ok(Source {file_template: template.to_string(), ..self.clone()})
+    }
+
+    /// The url of a file at the top of the repository (the manifest, changelog, and so on); `name` is used as is, so it should already be encoded
+    pub fn url(&self, name: &str) -> String {
+        format!("{}{}/{}", self.base_url, self.git_ref, name)
+    }
+
+    /// The url of the hash manifest
+    pub fn manifest_url(&self) -> String {
+        self.url(MANIFEST_FILENAME)
+    }
+
+    /// The url of a file under `TARGET_DIR`, given it's path relative to that directory and it's hash, built from the url template
+    pub fn file_url(&self, pathstring: &str, hash: &str) -> String {
+        self.expand(&self.file_template, pathstring, hash)
+    }
+
+    /// The url of the object holding a file with the given hash, in the content-addressed layout `update-manifest --objects` publishes
+    pub fn object_url(&self, hash: &str) -> String {
+        self.expand(OBJECT_URL_TEMPLATE, "", hash)
+    }
+
+    /// Fill in a url template's placeholders for a file
+    fn expand(&self, template: &str, pathstring: &str, hash: &str) -> String {
+        let mut url = String::new();
+        let mut rest = template;
+        // the template's been checked already, so every { has a matching } and a known name
+        while let Some(start) = rest.find('{') {
+            let end = start + rest[start..].find('}').unwrap();
+            url.push_str(&rest[..start]);
+            match &rest[start + 1..end] {
+                "base" => url.push_str(&self.base_url),
+                "ref" => url.push_str(&utils::percent_encode_pathstring(&self.git_ref)),
+                "path" => url.push_str(&utils::percent_encode_pathstring(pathstring)),
+                "hash" => url.push_str(&utils::percent_encode_segment(hash)),
+                // a hash that can't name an object leaves the url pointing at nothing
+                "object" => url.push_str(&utils::percent_encode_pathstring(&objects::object_name(hash).unwrap_or_default())),
+                _ => (),
+            }
+            rest = &rest[end + 1..];
+        }
+        url.push_str(rest);
+        url
+    }
+
+    /// If this source has published the commit it's manifest was built from, return a source pinned to that commit; otherwise return this source unchanged
+    ///
+    /// Reading the manifest and every file from one commit means a push in the middle of an update can't mix files from two different versions
+    pub fn pinned(&self, client: &reqwest::Client) -> Source {
+        // an older commit's pin can only point further back, so a source that's already at a commit stays there
+        if is_commit_id(&self.git_ref) {
+            return self.clone();
+        }
+        let pin = match self.fetch_text(client, REF_FILENAME) {
+            Some(p) => p,
+            None => return self.clone(),
+        };
+        if !is_commit_id(&pin) {
+            return self.clone();
+        }
+        let pinned = self.at_ref(&pin);
+        // a pin that wasn't moved when a new manifest was committed would keep everyone on the old version
+        let version_path = format!("{}/{}", TARGET_DIR, VERSION_FILENAME);
+        let version = |source: &Source| source.fetch_text(client, &version_path).and_then(|v| Version::parse(&v).ok());
+        if let (Some(latest), Some(at_pin)) = (version(self), version(&pinned)) {
+            if at_pin < latest {
+                println!("Warning: updates are pinned to version {}, but {} is newer; updating from {} instead.", at_pin.to_string(), latest.to_string(), self.git_ref);
+                return self.clone();
+            }
+        }
+        pinned
+    }
+
+    /// Download a small text file from the top of the repository, trimmed; None if it can't be had
+    fn fetch_text(&self, client: &reqwest::Client, name: &str) -> Option<String> {
+        let resp = client.get(&self.url(name)).send().ok()?;
+        if !resp.status().is_success() {
+            return None;
+        }
+        let mut text = String::new();
+        compression::decoded_body(resp).read_to_string(&mut text).ok()?;
+        Some(text.trim().to_string())
+    }
+}
+
+/// Return the source for a named update channel, or None if there's no such channel
+pub fn for_channel (name: &str) -> Option<Source> {
+    CHANNELS.iter()
+        .find(|&&(channel, _)| channel == name)
+        .map(|&(_, branch)| Source::new(REPO_RAW_URL, branch))
+}
+
+/// The names of the placeholders in a url template
+fn placeholders (template: &str) -> Result<Vec<String>, String> {
+    let mut names = vec![];
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(e) => start + e,
+            None => return Err("the url template has a { without a matching }".to_string()),
+        };
+        names.push(rest[start + 1..end].to_string());
+        rest = &rest[end + 1..];
+    }
+    Ok(names)
+}
+
+/// Returns true if `r` looks like a (possibly abbreviated) git commit id
+pub fn is_commit_id (r: &str) -> bool {
+    r.len() >= 7 && r.len() <= 64 && r.chars().all(|c| c.is_ascii_hexdigit())
+}
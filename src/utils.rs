@@ -2,55 +2,41 @@
 
 
 use std::path::Path;
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, BTreeMap};
 use std::error::Error;
+use std::fs;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use blake2_rfc::blake2b::blake2b;
+use crate::constants::{HASH_LENGTH, STANDARD_UPDATER_IGNORE_FILENAME, CUSTOM_UPDATER_IGNORE_FILENAME, PROTECT_FILENAME};
+use crate::compression;
 use checksums::util::relative_name;
-use gitignore::File;
+use gitignore::Pattern;
 use walkdir::WalkDir;
-use url::percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET};
-
-
-/// Given a path and a list of `gitignore::File` instances, return a BTreeSet of ignored files (as strings relative to the path given), ready for passing to checksums::ops::create_hashes
-pub fn ignored_files (path: &Path, ignore_files: Vec<File>) -> BTreeSet<String> {
-    // This function returns every file (as a string relative to the `path` arg)
-    // this is for use with checksums, since it only allows you to provide a BTreeSet of strings
-    // this has to walk `path` and check every file
-    /*
-This is really inefficient and I hate it, but...
-gitignore has an included_files method, which is_excluded calls every time. So I can't use is_excluded, because that would trigger a recursion on every call.
-So instead we call included_files, and check if each path I'm iterating over is in that list, which is what is_excluded does anyway; I'm just not needlessly recursing.
-    */
+use url::percent_encoding::{utf8_percent_encode, EncodeSet};
+
+
+/// Given a path and the ignore rules that apply under it, return a BTreeSet of ignored files (as strings relative to the path given), ready for passing to checksums::ops::create_hashes
+pub fn ignored_files (path: &Path, rules: &IgnoreRules) -> BTreeSet<String> {
+    // checksums only lets you provide a BTreeSet of strings, so this has to walk `path` and check every file
     let mut ignores: BTreeSet<String> = BTreeSet::new();
-    let mut included_files = vec![];
-    for f in &ignore_files {
-        let r = f.included_files().unwrap();
-        included_files.extend(r);
-        included_files.sort();
-    }
-    // now remove duplicate paths from the vec
-    included_files.dedup();
     let mut walker = WalkDir::new(path).max_open(15).into_iter();
     while let Some(e) = walker.next() {
         match e {
             Ok(entry) => {
-//                println!("Working on '{:?}'", entry.file_name());
-                // check to see if the path is excluded for each ignore file we were given
-                if included_files.contains(&entry.path().to_path_buf()) == false && entry.path() != path{
-                    ignores.insert(relative_name(path, entry.path()));
-                    // if this entry is  a directory, we can skip recursing through it, as gitignore says there's no included files here
-                    if entry.file_type().is_dir() {
-                        //println!("Skipping directory {:?}", entry.path());
+                if entry.path() == path {
+                    continue;
+                }
+                let name = relative_name(path, entry.path());
+                let is_dir = entry.file_type().is_dir();
+                // git's own directory is never part of the pack
+                if entry.file_name() == ".git" || rules.explain(&name, is_dir).ignored {
+                    ignores.insert(name);
+                    // if this entry is a directory, we can skip recursing through it, as nothing in an ignored directory can be included again
+                    if is_dir {
                         walker.skip_current_dir();
                     }
                 }
-/*
-                    for f in &ignore_files {
-                        let ignored_entry = f.is_excluded(entry.path()).unwrap();
-                        if ignored_entry && entry.path() != path {
-                            ignores.append(relative_name(path, entry.path()));
-                        }
-                    }
-*/
             },
             Err(e) => panic!("Error traversing directory: {}", e.description()),
         }
@@ -58,16 +44,387 @@ So instead we call included_files, and check if each path I'm iterating over is
     ignores
 }
 
+/// One pattern from an ignore file, along with where it came from
+pub struct IgnoreRule {
+    pub file: String, // the name of the ignore file
+    pub line: usize,
+    pub text: String, // the pattern as written
+    pattern: PathPattern,
+}
+
+impl IgnoreRule {
+    /// Describe where this rule came from, for diagnostics
+    pub fn describe(&self) -> String {
+        format!("{} line {}: {}", self.file, self.line, self.text)
+    }
+}
+
+/// Why a path is or isn't ignored
+pub struct IgnoreExplanation<'a> {
+    pub ignored: bool,
+    pub decided_by: Option<&'a IgnoreRule>, // the rule that decided it, if any rule matched
+    pub via: Option<String>, // the directory the deciding rule matched, if it matched one the path is in rather than the path itself
+    pub overridden: Vec<&'a IgnoreRule>, // rules that matched but were overridden by a later one
+}
+
+/// The rules from the standard and custom ignore files, in the order they apply
+///
+/// As with git, the last pattern that matches a path decides whether it's ignored, a negated pattern includes a path again, and nothing inside an ignored directory can be included again
+#[derive(Default)]
+pub struct IgnoreRules {
+    rules: Vec<IgnoreRule>,
+}
+
+impl IgnoreRules {
+    /// Load the standard and then the custom ignore files from `cp_path`, skipping any that don't exist
+    pub fn load(cp_path: &Path) -> IgnoreRules {
+        IgnoreRules::load_files(cp_path, &[STANDARD_UPDATER_IGNORE_FILENAME, CUSTOM_UPDATER_IGNORE_FILENAME])
+    }
+
+    /// Load the files called `names` from `cp_path`, in order, skipping any that don't exist
+    pub fn load_files(cp_path: &Path, names: &[&str]) -> IgnoreRules {
+        let mut rules = IgnoreRules::default();
+        for name in names {
+            let p = cp_path.join(name);
+            if p.exists() {
+                let text = fs::read_to_string(&p).unwrap_or_else(|why| panic!("Can't read {}: {}", p.display(), why));
+                rules.add(name, &text);
+            }
+        }
+        rules
+    }
+
+    /// Add the rules in `text`, an ignore file called `name`; lines that aren't valid patterns are skipped
+    pub fn add(&mut self, name: &str, text: &str) {
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Ok(pattern) = PathPattern::new(line) {
+                self.rules.push(IgnoreRule {file: name.to_string(), line: n + 1, text: line.to_string(), pattern});
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Work out whether `path` (relative, / separated) is ignored, and which rules decided it
+    pub fn explain(&self, path: &str, is_dir: bool) -> IgnoreExplanation<'_> {
+        let parts: Vec<&str> = path.split('/').collect();
+        let mut explanation = IgnoreExplanation {ignored: false, decided_by: None, via: None, overridden: vec![]};
+        // check each directory the path is in, then the path itself; the first level that's ignored settles it
+        for i in 0..parts.len() {
+            let prefix = parts[..i + 1].join("/");
+            let level_is_dir = i + 1 < parts.len() || is_dir;
+            let matching: Vec<&IgnoreRule> = self.rules.iter().filter(|r| r.pattern.matches_exactly(&prefix, level_is_dir)).collect();
+            let last = match matching.last() {
+                Some(r) => *r,
+                None => continue,
+            };
+            if i + 1 < parts.len() && last.pattern.negation {
+                continue; // the directory's included, so it's up to what's inside it
+            }
+            explanation.ignored = !last.pattern.negation;
+            explanation.decided_by = Some(last);
+            explanation.via = if i + 1 < parts.len() { Some(prefix) } else { None };
+            explanation.overridden = matching[..matching.len() - 1].to_vec();
+            if explanation.ignored {
+                break;
+            }
+        }
+        explanation
+    }
+}
+
+/// Of `paths`, return the ones the user has protected in the protect file under `cp_path`, which uses ignore file syntax
+pub fn protected_files<'a, I: IntoIterator<Item = &'a String>> (cp_path: &Path, paths: I) -> BTreeSet<String> {
+    let rules = IgnoreRules::load_files(cp_path, &[PROTECT_FILENAME]);
+    if rules.is_empty() {
+        return BTreeSet::new();
+    }
+    paths.into_iter()
+        .filter(|p| rules.explain(p, false).ignored) // "ignored" here meaning the protect file matches it
+        .cloned()
+        .collect()
+}
+
+/// Explain, for people, why `path` (relative to `cp_path`) is or isn't ignored by the ignore files there
+pub fn explain_ignore (cp_path: &Path, path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let path = path.trim_start_matches("./").trim_end_matches('/');
+    let rules = IgnoreRules::load(cp_path);
+    let explanation = rules.explain(path, cp_path.join(path).is_dir());
+    let mut lines = vec![format!("{} is {}.", path, if explanation.ignored { "ignored" } else { "not ignored" })];
+    match explanation.decided_by {
+        Some(rule) => {
+            let kind = if rule.pattern.negation { ", which includes it again" } else { "" };
+            match explanation.via {
+                Some(ref dir) => lines.push(format!("Decided by {}{}, which matches the directory {} it's in.", rule.describe(), kind, dir)),
+                None => lines.push(format!("Decided by {}{}.", rule.describe(), kind)),
+            }
+        },
+        None if rules.is_empty() => lines.push("There aren't any ignore files.".to_string()),
+        None => lines.push("No rule matches it.".to_string()),
+    }
+    for rule in &explanation.overridden {
+        lines.push(format!("Overridden: {}", rule.describe()));
+    }
+    lines.join("\n")
+}
+
+/// List every ignored path under `cp_path`; directories end in /, and what's inside them isn't listed
+pub fn list_ignored (cp_path: &Path) -> Vec<String> {
+    ignored_files(cp_path, &IgnoreRules::load(cp_path)).into_iter()
+        .map(|p| if cp_path.join(&p).is_dir() { format!("{}/", p) } else { p })
+        .collect()
+}
+
+/// The bytes that get percent encoded in a path segment: everything but the characters RFC 3986 says never need it, so a literal %, ?, # or anything else in a file name survives
+#[derive(Clone)]
+struct SegmentEncodeSet;
+
+impl EncodeSet for SegmentEncodeSet {
+    fn contains(&self, byte: u8) -> bool {
+        !(byte.is_ascii_alphanumeric() || b"-._~".contains(&byte))
+    }
+}
+
+/// Percent encode a single url path segment
+pub fn percent_encode_segment (segment: &str) -> String {
+    utf8_percent_encode(segment, SegmentEncodeSet).collect()
+}
+
 /// Given a string, split it up on the / character, url percent encode each substring, then reassenble them
 pub fn percent_encode_pathstring (pathstring: &str) -> String {
     // This takes paths as strings rather than path objects
     let substrings: Vec<&str> = pathstring.split('/').collect();
     let mut encoded_subs: Vec<String>= Vec::new(); // vec to hold encoded substrings
     for sub in substrings {
-        let i = utf8_percent_encode(sub, DEFAULT_ENCODE_SET);
-        let encoded_sub: String = i.collect();
-        encoded_subs.push(encoded_sub);
+        encoded_subs.push(percent_encode_segment(sub));
     }
     let result= encoded_subs.join("/");
     result
 }
+
+/// Returns true if `hash` looks like one checksums made with `ALGO`: upper case hex of the right length
+pub fn is_valid_hash (hash: &str) -> bool {
+    hash.len() == HASH_LENGTH && hash.chars().all(|c| c.is_ascii_digit() || ('A'..='F').contains(&c))
+}
+
+/// Returns true if `hash` is the all-dashes placeholder checksums uses for ignored files
+pub fn is_placeholder_hash (hash: &str) -> bool {
+    !hash.is_empty() && hash.chars().all(|c| c == '-')
+}
+
+/// Given a hash manifest, return only the entries that have real hashes (skipping ignored files), so two manifests can be compared by content
+pub fn content_hashes (hashes: &BTreeMap<String, String>) -> BTreeMap<&String, &String> {
+    hashes.iter()
+        .filter(|&(_, hash)| !is_placeholder_hash(hash))
+        .collect()
+}
+
+/// Returns true if `location` looks like something that should be downloaded rather than read from disk
+pub fn is_url (location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+/// Load and parse a json document from either a URL or a local file
+pub fn load_json<T: DeserializeOwned> (location: &str) -> Result<T, String> {
+    if is_url(location) {
+        fetch_json(&compression::http_client(), location)
+    } else {
+        let file = fs::File::open(location).map_err(|why| format!("Can't open '{}': {}", location, why))?;
+        serde_json::from_reader(file).map_err(|why| format!("Error parsing '{}': {}", location, why))
+    }
+}
+
+/// Load and parse a json file from disk, returning the default (an empty index, for instance) if it doesn't exist yet
+pub fn load_json_file<T: DeserializeOwned + Default> (path: &Path) -> Result<T, String> {
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    load_json(&path.to_string_lossy())
+}
+
+/// Write `value` out to `path` as json; `what` says what it is, for error messages
+pub fn save_json<T: Serialize> (path: &Path, value: &T, what: &str) -> Result<(), String> {
+    let j = serde_json::to_string_pretty(value).map_err(|why| format!("Couldn't create a json representation of the {}: {}", what, why))?;
+    fs::write(path, j).map_err(|why| format!("Couldn't write to {}: {}", path.display(), why))
+}
+
+/// Like `load_json`, but a document that doesn't exist (a missing file, or a 404) is None rather than an error, so files that are optional to publish can be told apart from ones that couldn't be read
+pub fn load_optional_json<T: DeserializeOwned> (location: &str) -> Result<Option<T>, String> {
+    if is_url(location) {
+        fetch_optional_json(&compression::http_client(), location)
+    } else if !Path::new(location).exists() {
+        Ok(None)
+    } else {
+        load_json(location).map(Some)
+    }
+}
+
+/// Download and parse a json document
+pub fn fetch_json<T: DeserializeOwned> (client: &reqwest::Client, url: &str) -> Result<T, String> {
+    fetch_optional_json(client, url)?.ok_or_else(|| format!("Can't retrieve '{}': the server returned status code {}", url, reqwest::StatusCode::NotFound))
+}
+
+/// Download and parse a json document, or return None if the server says it doesn't exist
+pub fn fetch_optional_json<T: DeserializeOwned> (client: &reqwest::Client, url: &str) -> Result<Option<T>, String> {
+    let resp = match client.get(url).send() {
+        Ok(r) => r,
+        Err(why) => return Err(format!("Can't retrieve '{}': {}", url, why)),
+    };
+    if resp.status() == reqwest::StatusCode::NotFound {
+        return Ok(None);
+    }
+    if !resp.status().is_success() {
+        return Err(format!("Can't retrieve '{}': the server returned status code {}", url, resp.status()));
+    }
+    serde_json::from_reader(compression::decoded_body(resp)).map(Some).map_err(|why| format!("Error parsing '{}': {}", url, why))
+}
+
+/// Given the program's arguments, return the value passed for `flag` (either as `--flag value` or `--flag=value`)
+pub fn arg_value (args: &[String], flag: &str) -> Option<String> {
+    let prefix = format!("{}=", flag);
+    for (i, a) in args.iter().enumerate() {
+        if a == flag {
+            return args.get(i + 1).cloned();
+        } else if a.starts_with(&prefix) {
+            return Some(a[prefix.len()..].to_string());
+        }
+    }
+    None
+}
+
+/// Hash a buffer the same way checksums hashes files on disk with `ALGO`: a 64 byte BLAKE2b digest, in upper case hex
+pub fn hash_bytes (bytes: &[u8]) -> String {
+    checksums::hash_string(blake2b(64, &[], bytes).as_bytes())
+}
+
+/// An ignore-style pattern, for matching against relative paths without touching the disk
+pub struct PathPattern {
+    pattern: Pattern<'static>,
+    pub negation: bool,
+}
+
+impl PathPattern {
+    pub fn new(raw: &str) -> Result<PathPattern, String> {
+        let negation = raw.starts_with('!');
+        let mut raw = raw.trim_start_matches('!').trim().to_string();
+        // patterns are matched against paths starting with /, so an anchored pattern needs one too
+        if raw.trim_end_matches('/').contains('/') && !raw.starts_with('/') {
+            raw.insert(0, '/');
+        }
+        let pattern = Pattern::new(&raw, Path::new("/")).map_err(|why| format!("bad pattern '{}': {:?}", raw, why))?;
+        Ok(PathPattern {pattern, negation})
+    }
+
+    /// Returns true if the pattern (ignoring negation) matches `path` (relative, / separated), or any of the directories it's in
+    pub fn matches(&self, path: &str) -> bool {
+        let parts: Vec<&str> = path.split('/').collect();
+        (0..parts.len()).any(|i| self.matches_exactly(&parts[..i + 1].join("/"), i + 1 < parts.len()))
+    }
+
+    /// Returns true if the pattern (ignoring negation) matches `path` itself, which is a directory if `is_dir` is set
+    pub fn matches_exactly(&self, path: &str, is_dir: bool) -> bool {
+        // is_excluded flips it's answer for negated patterns, but negation is up to the caller
+        self.pattern.is_excluded(Path::new(&format!("/{}", path)), is_dir) != self.pattern.negation
+    }
+}
+
+/// A section of a pattern file: the `[header]` it started with, anything after that header on the same line, and it's patterns
+pub struct PatternSection {
+    pub header: String,
+    pub rest: String,
+    pub patterns: Vec<PathPattern>,
+}
+
+/// Parse a file of ignore-style patterns split into sections by `[header]` lines, as used by the policy and component files
+pub fn parse_pattern_sections (text: &str) -> Result<Vec<PatternSection>, String> {
+    let mut sections: Vec<PatternSection> = vec![];
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            let end = match line.find(']') {
+                Some(e) => e,
+                None => return Err(format!("line {}: unclosed [ in '{}'", n + 1, line)),
+            };
+            sections.push(PatternSection {
+                header: line[1..end].trim().to_string(),
+                rest: line[end + 1..].trim().to_string(),
+                patterns: vec![],
+            });
+            continue;
+        }
+        let pattern = PathPattern::new(line).map_err(|why| format!("line {}: {}", n + 1, why))?;
+        match sections.last_mut() {
+            Some(section) => section.patterns.push(pattern),
+            None => return Err(format!("line {}: '{}' comes before any [section] header", n + 1, line)),
+        }
+    }
+    Ok(sections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::ALGO;
+
+    #[test]
+    fn hash_bytes_matches_hashing_a_file () {
+        let p = std::env::temp_dir().join(format!("chatpack-updater-hash-test-{}", std::process::id()));
+        fs::write(&p, b"some file contents").unwrap();
+        let expected = checksums::hash_file(&p, ALGO);
+        fs::remove_file(&p).unwrap();
+        assert_eq!(hash_bytes(b"some file contents"), expected);
+    }
+
+    fn rules (text: &str) -> IgnoreRules {
+        let mut rules = IgnoreRules::default();
+        rules.add("test.update-ignore", text);
+        rules
+    }
+
+    #[test]
+    fn the_last_matching_rule_decides () {
+        let rules = rules("*.lua\n!vars.lua\nvars.*\n");
+        let explanation = rules.explain("vars.lua", false);
+        assert!(explanation.ignored);
+        assert_eq!(explanation.decided_by.map(|r| r.line), Some(3));
+        assert_eq!(explanation.overridden.iter().map(|r| r.line).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(!rules.explain("readme.txt", false).ignored);
+    }
+
+    #[test]
+    fn negated_rules_include_files_again () {
+        let rules = rules("*.log\n!keep.log\n");
+        let explanation = rules.explain("keep.log", false);
+        assert!(!explanation.ignored);
+        assert_eq!(explanation.decided_by.map(|r| r.text.as_str()), Some("!keep.log"));
+        assert_eq!(explanation.overridden.iter().map(|r| r.line).collect::<Vec<_>>(), vec![1]);
+        assert!(rules.explain("other.log", false).ignored);
+    }
+
+    #[test]
+    fn nothing_in_an_ignored_directory_is_included_again () {
+        let rules = rules("logs/\n!logs/keep.log\n");
+        let explanation = rules.explain("logs/keep.log", false);
+        assert!(explanation.ignored);
+        assert_eq!(explanation.decided_by.map(|r| r.line), Some(1));
+        assert_eq!(explanation.via, Some("logs".to_string()));
+    }
+
+    #[test]
+    fn unmatched_paths_arent_ignored () {
+        let rules = rules("*.log\n");
+        let explanation = rules.explain("lib/file.lua", false);
+        assert!(!explanation.ignored);
+        assert!(explanation.decided_by.is_none());
+    }
+}
@@ -2,7 +2,8 @@
 
 use chrono::{Local, DateTime, Datelike};
 
-#[derive(Debug)]
+// field order matters here: deriving the comparison traits compares year, then month, then day, then patch
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Version {
   year: i16,
   month: i8,
@@ -15,18 +16,29 @@ impl Version {
   pub fn from_string(version_string: &str) -> Version {
     // this method creates and returns a new version struct;
     // it accepts a string, an example of which is "2017.12.10.1"
+    match Version::parse(version_string) {
+      Ok(v) => v,
+      Err(why) => panic!("{}", why),
+    }
+  }
+
+  pub fn parse(version_string: &str) -> Result<Version, String> {
+    // like from_string, but returns an error instead of panicking on a malformed version string
     let elements: Vec<&str> = version_string.trim().split('.').collect();
+    if elements.len() != 4 {
+      return Err(format!("'{}' isn't a valid version; expected year.month.day.patch", version_string.trim()));
+    }
     let year: i16 = elements[0].trim()
       .parse::<i16>()
-      .expect("Error converting the year field of version to an integer");
+      .map_err(|_| "Error converting the year field of version to an integer".to_string())?;
     let month: i8 = elements[1].trim().parse::<i8>()
-      .expect("Error converting the month field of version to an integer");
+      .map_err(|_| "Error converting the month field of version to an integer".to_string())?;
     let day: i8 = elements[2].trim().parse::<i8>()
-      .expect("Error converting the day field of version to an integer");
+      .map_err(|_| "Error converting the day field of version to an integer".to_string())?;
     let patch:i8 = elements[3].trim().parse()
-      .expect("Error converting the patch field of version into an integer");
+      .map_err(|_| "Error converting the patch field of version into an integer".to_string())?;
     let v: Version = Version {year, month, day, patch};
-    return v;
+    return Ok(v);
   }
 
   pub fn new () -> Version {
@@ -0,0 +1,31 @@
+// the version index: every published version of chatpack, mapped to the commit it's manifest and files can be fetched from
+
+use std::collections::BTreeMap;
+use crate::utils;
+use crate::version::Version;
+
+pub type VersionIndex = BTreeMap<String, String>;
+
+/// Load a version index from either a local file or a URL
+pub fn load (location: &str) -> Result<VersionIndex, String> {
+    utils::load_json(location)
+}
+
+/// Every version in the index, oldest first, along with it's commit
+pub fn sorted (index: &VersionIndex) -> Vec<(Version, String)> {
+    let mut versions: Vec<(Version, String)> = index.iter()
+        .filter_map(|(v, commit)| Version::parse(v).ok().map(|v| (v, commit.clone())))
+        .collect();
+    versions.sort_by(|a, b| a.0.cmp(&b.0));
+    versions
+}
+
+/// The commit `version` was published at, if it's in the index
+pub fn commit_for (index: &VersionIndex, version: &Version) -> Option<String> {
+    sorted(index).into_iter().find(|&(ref v, _)| v == version).map(|(_, commit)| commit)
+}
+
+/// The newest version older than `version`, along with it's commit
+pub fn previous (index: &VersionIndex, version: &Version) -> Option<(Version, String)> {
+    sorted(index).into_iter().filter(|&(ref v, _)| v < version).last()
+}
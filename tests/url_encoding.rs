@@ -0,0 +1,55 @@
+// property tests for building urls out of manifest paths
+
+extern crate chatpack_updater;
+extern crate proptest;
+extern crate url;
+
+use proptest::prelude::*;
+use url::Url;
+use url::percent_encoding::percent_decode;
+use chatpack_updater::paths;
+use chatpack_updater::source::Source;
+use chatpack_updater::utils;
+
+/// Manifest paths: one to four components of anything but /, backslash and NUL, kept if they're safe to write to
+fn manifest_path () -> impl Strategy<Value = String> {
+    prop::collection::vec(r"[^/\\\x00]{1,20}", 1..5)
+        .prop_map(|parts| parts.join("/"))
+        .prop_filter("unsafe manifest path", |p| paths::unsafe_reason(p).is_none())
+}
+
+fn decode (s: &str) -> String {
+    percent_decode(s.as_bytes()).decode_utf8().unwrap().into_owned()
+}
+
+proptest! {
+    #[test]
+    fn paths_round_trip_through_encoding (path in manifest_path()) {
+        prop_assert_eq!(decode(&utils::percent_encode_pathstring(&path)), path);
+    }
+
+    #[test]
+    fn encoded_paths_only_use_unreserved_characters (path in manifest_path()) {
+        let encoded = utils::percent_encode_pathstring(&path);
+        prop_assert!(encoded.bytes().all(|b| b.is_ascii_alphanumeric() || b"-._~%/".contains(&b)));
+        // the only slashes left are the ones between components
+        prop_assert_eq!(encoded.matches('/').count(), path.matches('/').count());
+    }
+
+    #[test]
+    fn file_urls_keep_each_component_as_a_segment (path in manifest_path(), hash in "[0-9a-f]{64}") {
+        let url = Url::parse(&Source::default().file_url(&path, &hash)).unwrap();
+        prop_assert_eq!(url.query(), None);
+        prop_assert_eq!(url.fragment(), None);
+        let segments: Vec<String> = url.path_segments().unwrap().map(decode).collect();
+        let components: Vec<String> = path.split('/').map(String::from).collect();
+        prop_assert!(segments.ends_with(&components));
+    }
+
+    #[test]
+    fn hash_templates_put_the_hash_in_the_url (path in manifest_path(), hash in "[0-9a-f]{64}") {
+        let source = Source::default().with_template("{base}{ref}/objects/{hash}").unwrap();
+        let url = Url::parse(&source.file_url(&path, &hash)).unwrap();
+        prop_assert_eq!(url.path_segments().unwrap().last(), Some(hash.as_str()));
+    }
+}